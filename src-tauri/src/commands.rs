@@ -1,32 +1,112 @@
-use argon2::Argon2;
-use std::{fs, path};
+use argon2::{
+    password_hash::rand_core::{OsRng, RngCore},
+    Argon2,
+};
+use serde::Serialize;
+use std::{path, sync::Arc};
+use tauri::Manager;
+use zeroize::Zeroizing;
 
 use crate::{
-    config::Config,
+    config::{self, Config, MasterKey, StartupNotices},
+    parity::{self, remove_parity, PARITY_FILE},
+    payload::Payload,
+    scrub::{scrub_vaults, ScrubKeys},
+    secret::{Password, SecretKey},
     utils::{
-        append_to_vaults, decrypt_file, derive_key, generate_hash_salt, lock_vault_util,
-        reconstruct_files, verify_password,
+        add_path, add_path_as, append_to_vaults, canonical_path, derive_key, encrypt_directory,
+        generate_hash_salt, hash_with_salt, is_reserved_name, lock_vault_util, read_vault_info,
+        remove_decoy_util, restore_file, restore_files, retain_vaultfile, retained_vaultfile_opens,
+        rewrite_vaultfile, secure_delete, set_decoy_util, set_hidden_util, vault_slot_opens,
+        verify_password, OpenedVault, Storage, RETAINED_VAULTFILE,
+    },
+    vaultfile::{self, VaultInfo},
+    viewmodel::{
+        check_header, DamagedVaultViewModel, RestoredFileViewModel, VaultFileViewModel,
+        VaultStatus, VaultViewModel, VerifyReportViewModel,
     },
-    viewmodel::VaultViewModel,
+    volume,
 };
 
+// The header of a vaultfile has to fit into its first volume.
+const MIN_VOLUME_SIZE: u64 = 1024 * 1024;
+
+// A year. Longer intervals would never come around while the app is open.
+const MAX_SCRUB_INTERVAL: u64 = 24 * 365;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MasterPasswordStatus {
+    is_encrypted: bool,
+    is_unlocked: bool,
+}
+
 #[tauri::command]
-pub fn get_vaults(handle: tauri::AppHandle) -> Vec<VaultViewModel> {
+pub fn get_vaults(handle: tauri::AppHandle) -> Result<Vec<VaultViewModel>, String> {
     VaultViewModel::get_from_file(handle)
 }
 
+// Messages about what happened while the app started, such as a restored configfile.
+// Each message is returned only once.
+#[tauri::command]
+pub fn get_startup_notices(handle: tauri::AppHandle) -> Vec<String> {
+    let notices = handle.state::<StartupNotices>();
+    let notices = std::mem::take(&mut *notices.0.lock().unwrap());
+    notices
+}
+
+// Tells the frontend whether to ask for the master password at launch.
+#[tauri::command]
+pub fn get_master_password_status(
+    handle: tauri::AppHandle,
+) -> Result<MasterPasswordStatus, String> {
+    let is_encrypted = config::is_encrypted(&handle).map_err(|e| e.to_string())?;
+    let is_unlocked = handle.state::<MasterKey>().0.lock().unwrap().is_some();
+
+    Ok(MasterPasswordStatus {
+        is_encrypted,
+        is_unlocked,
+    })
+}
+
+#[tauri::command]
+pub fn unlock_master_password(password: Password, handle: tauri::AppHandle) -> Result<(), String> {
+    config::unlock_configfile(&handle, &password)
+}
+
+// Encrypt the configfile under a new master password. Changing an existing master password
+// needs the current one.
 #[tauri::command]
-pub fn remove_vault(path: &str, handle: tauri::AppHandle) -> Result<(), String> {
+pub fn set_master_password(
+    current_password: Option<Password>,
+    new_password: Password,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    if config::is_encrypted(&handle).map_err(|e| e.to_string())? {
+        let current_password = current_password.ok_or("The current master password is needed!")?;
+        config::unlock_configfile(&handle, &current_password)?;
+    }
+
+    config::set_master_password(&handle, &new_password)
+}
+
+#[tauri::command]
+pub fn remove_vault(id: &str, handle: tauri::AppHandle) -> Result<(), String> {
     let mut configfile = Config::from_json(handle.clone())
-        .expect("Could not access the configfile for deleting a vault.");
-    let index = configfile.index_of_path(path); // The index of the item to remove
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+    let index = configfile.index_of_id(id)?; // The index of the item to remove
 
-    if configfile.index_locked(index) {
+    // A wiped vault has nothing left to protect, so it may be removed while locked.
+    if configfile.index_locked(index) && !configfile.index_wiped(index) {
         return Err("Cannot remove a locked vault!".into());
     }
 
+    // The vaultfile kept while unlocked is left in the directory. It is the only copy of the
+    // decoy and of any hidden vault, and the vault can be imported again from it.
+
     // Remove the items
     configfile.remove_index(index);
+    forget_scrub_key(id, &handle);
 
     configfile
         .to_json(handle)
@@ -35,51 +115,273 @@ pub fn remove_vault(path: &str, handle: tauri::AppHandle) -> Result<(), String>
     Ok(())
 }
 
+// Add the vault in a directory back to the app from the description in its vaultfile.
+// The vaultfile does not keep the name or the password hash, so the vault gets the given name
+// and the password is checked against the vaultfile.
+#[tauri::command]
+pub fn import_vault(
+    path: &str,
+    name: &str,
+    password: Password,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    import_into(&mut configfile, path::Path::new(path), name, &password)?;
+
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Look for vaultfiles anywhere under root that belong to vaults not in the app yet.
+// Returns their directories, each is imported with import_vault as that needs its password.
+#[tauri::command]
+pub fn scan_for_vaults(root: &str, handle: tauri::AppHandle) -> Result<Vec<String>, String> {
+    let configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let mut found = vec![];
+    for entry in walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let name = entry.file_name();
+        let is_vaultfile = name
+            .to_str()
+            .is_some_and(|name| ["vaultfile", "vaultfile.001", RETAINED_VAULTFILE].contains(&name));
+        if !entry.file_type().is_file() || !is_vaultfile {
+            continue;
+        }
+
+        // A directory holds only one of these, skip the others if they are leftovers.
+        let dir = entry.path().parent().unwrap();
+        if found.iter().any(|path| path::Path::new(path) == dir) {
+            continue;
+        }
+
+        match new_vault_in(&configfile, dir) {
+            Ok(_) => found.push(dir.to_str().unwrap().to_string()),
+            Err(e) => println!("Skipping {}: {}", dir.display(), e),
+        }
+    }
+
+    Ok(found)
+}
+
+// Point a vault at the directory it was moved to. The directory must hold the same vault.
+#[tauri::command]
+pub fn relink_vault(id: &str, path: &str, handle: tauri::AppHandle) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+    let index = configfile.index_of_id(id)?;
+
+    let path = canonical_path(path)?;
+    let (info, is_locked) = read_vault_info(path::Path::new(&path))?;
+    if info.id != id {
+        return Err("The directory holds a different vault!".to_string());
+    }
+    if check_header(&configfile.vaults[index], path::Path::new(&path)) == Some(false) {
+        return Err(
+            "The header of the vaultfile in the directory does not authenticate!".to_string(),
+        );
+    }
+    if configfile.path_exists(&path) && configfile.get_path(index) != path {
+        return Err("Another vault is already in this directory!".to_string());
+    }
+
+    configfile.set_path(index, &path);
+    if is_locked {
+        configfile.mark_locked(index);
+    } else {
+        configfile.mark_unlocked(index);
+    }
+
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Mark a vault locked or unlocked to match the files in its directory.
+#[tauri::command]
+pub fn reconcile_vault(id: &str, handle: tauri::AppHandle) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+    let index = configfile.index_of_id(id)?;
+    if VaultStatus::of(&configfile.vaults[index]) != VaultStatus::StateMismatch {
+        return Err("The vault state already matches its directory!".to_string());
+    }
+
+    // A vaultfile under its own name or in volumes means the vault was locked, the retained
+    // one that it was unlocked.
+    if volume::has_vaultfile(path::Path::new(configfile.get_path(index))) {
+        configfile.mark_locked(index);
+    } else {
+        configfile.mark_unlocked(index);
+    }
+
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
 #[tauri::command]
 pub fn create_secure_vault(
     name: &str,
     path: &str,
-    password: &str,
+    password: Password,
+    decoy_password: Option<Password>,
+    decoy_path: Option<String>,
     handle: tauri::AppHandle,
 ) -> Result<(), String> {
-    let configfile = Config::from_json(handle.clone()).expect("Could not read the configfile!");
+    let configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+    let path = &canonical_path(path)?;
     if configfile.path_exists(path) {
         return Err("Path already added as a vault!".into());
     }
+    // Locking would put the vaultfile of the vault that is already there in its place.
+    if volume::has_vaultfile(path::Path::new(path)) {
+        return Err(
+            "The directory already holds a locked vault. Import it instead of creating a new one."
+                .into(),
+        );
+    }
+
+    let decoy = match (decoy_password, decoy_path) {
+        (Some(decoy_password), Some(decoy_path)) => {
+            check_decoy(path, &password, &decoy_password, &decoy_path)?;
+            Some((decoy_password, decoy_path))
+        }
+        (None, None) => None,
+        _ => return Err("A decoy needs both a password and a directory!".into()),
+    };
 
     let argon2 = Argon2::default();
-    let (hash, salt) = generate_hash_salt(&argon2, password);
+    let (hash, salt) = generate_hash_salt(&argon2, &password);
 
-    let mut key_bytes = [0_u8; 32];
-    derive_key(argon2, password, salt.as_str(), &mut key_bytes);
+    let key_bytes = derive_key(argon2, &password, salt.as_str());
 
-    lock_vault_util(path, &key_bytes)?;
+    // The decoy files are encrypted before the vault is locked, so a decoy directory that
+    // cannot be read leaves the vault directory untouched.
+    let decoy = match decoy {
+        Some((decoy_password, decoy_path)) => {
+            let decoy_key = derive_key(Argon2::default(), &decoy_password, salt.as_str());
+            let payload = encrypt_directory(&decoy_path, &decoy_key, 0)?;
+            Some((decoy_key, payload))
+        }
+        None => None,
+    };
 
-    append_to_vaults(name, path, &hash, salt, handle);
+    let info = VaultInfo {
+        id: config::new_vault_id(),
+        salt: salt.as_str().to_string(),
+        seed: Some(OsRng.next_u64()),
+    };
+    let metadata_key = config::new_metadata_key();
+    let metadata_key_bytes = Zeroizing::new(hex::decode(&metadata_key).unwrap());
+    // New vaults have no compression, parity or volumes until they are turned on.
+    let storage = Storage {
+        header_key: Some(vaultfile::header_key(&metadata_key_bytes)),
+        ..Storage::default()
+    };
+    lock_vault_util(
+        path,
+        &key_bytes,
+        None,
+        &info,
+        Some(&metadata_key_bytes),
+        0,
+        storage,
+    )?;
+
+    // The vault is added before its decoy is set, so it can still be unlocked if that fails.
+    append_to_vaults(path, name, &hash, &info, &metadata_key, handle);
+
+    if let Some((decoy_key, payload)) = decoy {
+        if let Err(e) = set_decoy_util(
+            path,
+            &key_bytes,
+            &decoy_key,
+            &payload,
+            &info,
+            &metadata_key_bytes,
+            storage,
+        ) {
+            return Err(format!(
+                "The vault was created, but its decoy could not be set: {}",
+                e
+            ));
+        }
+    }
 
     Ok(())
 }
 
+// The vault is locked with the password it was unlocked with, so the files go back where they
+// came from. Giving the hidden password keeps the hidden vault when locking the vault or its
+// decoy. Without it the hidden vault is overwritten.
 #[tauri::command]
-pub fn lock_vault(path: &str, password: &str, handle: tauri::AppHandle) -> Result<(), String> {
-    let mut configfile =
-        Config::from_json(handle.clone()).expect("Could not get the configfile from json!");
+pub fn lock_vault(
+    id: &str,
+    password: Password,
+    hidden_password: Option<Password>,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
 
     let argon2 = Argon2::default();
-    let index = configfile.index_of_path(path); // The index of the vault in config
+    let index = configfile.index_of_id(id)?; // The index of the vault in config
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    if configfile.index_locked(index) {
+        return Err("The vault is already locked!".to_string());
+    }
+    check_vault_state(&configfile, index)?;
+
+    let metadata_key = configfile.metadata_key(index);
+    let path = configfile.get_path(index);
     let hash = configfile.get_hash(index);
     let salt = configfile.get_salt(index);
 
-    if !verify_password(&argon2, hash.to_string(), password) {
+    let is_vault_password = verify_password(&argon2, hash.to_string(), &password);
+    let key_bytes = derive_key(argon2, &password, salt);
+
+    // Any other password may only lock the decoy or hidden vault it unlocked.
+    if !is_vault_password && !retained_vaultfile_opens(path::Path::new(path), &key_bytes) {
         return Err("Incorrect password!".to_string());
     }
+    if configfile.unlocked_with_other_key(index, &key_bytes) {
+        return Err("Lock the vault with the password it was unlocked with!".to_string());
+    }
 
-    let mut key_bytes = [0u8; 32];
-    derive_key(argon2, password, salt, &mut key_bytes);
+    let hidden_key = hidden_password
+        .map(|hidden_password| derive_key(Argon2::default(), &hidden_password, salt));
 
-    lock_vault_util(path, &key_bytes)?;
+    let info = configfile.vault_info(index);
+    let compression_level = configfile.vaults[index].compression_level;
+    lock_vault_util(
+        path,
+        &key_bytes,
+        hidden_key.as_deref(),
+        &info,
+        Some(&metadata_key),
+        compression_level,
+        configfile.vaults[index].storage(),
+    )?;
 
     configfile.mark_locked(index);
+    configfile.record_locked(index);
+    configfile.record_header_mac(index);
     if let Err(e) = configfile.to_json(handle) {
         return Err(format!("Error writing configfile back to json: {}", e));
     }
@@ -87,52 +389,825 @@ pub fn lock_vault(path: &str, password: &str, handle: tauri::AppHandle) -> Resul
     Ok(())
 }
 
+// Returns how each restored file compares with the hash it was locked with.
 #[tauri::command]
-pub fn unlock_vault(path: &str, password: &str, handle: tauri::AppHandle) -> Result<(), String> {
+pub fn unlock_vault(
+    id: &str,
+    password: Password,
+    handle: tauri::AppHandle,
+) -> Result<Vec<RestoredFileViewModel>, String> {
     // Read the config
     let mut configfile = Config::from_json(handle.clone())
-        .expect("Could not open the configfile for unlocking the vault!");
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    check_vault_state(&configfile, index)?;
+
+    let path = configfile.get_path(index).to_string();
+    let path_p = path::Path::new(&path);
+
+    // The password opens either the vault or its decoy. Both unlock the same way.
+    let (opened, key_bytes) = open_locked_vault(&mut configfile, index, &password, handle.clone())?;
+    let checks = restore_files(&opened.payload, &key_bytes, path_p)?;
+
+    // Keep the vaultfile after decryption, the slot that was not opened is needed to lock again.
+    retain_vaultfile(path_p)?;
+    // The parity is renewed when the vault is locked again.
+    if let Err(e) = remove_parity(path_p) {
+        println!("{}", e);
+    }
+
+    // Scrubbing only checks locked vaults, so there is no reason to keep its key any longer.
+    forget_scrub_key(id, &handle);
+    // Mark the path unlocked in and save the config
+    configfile.mark_unlocked(index);
+    configfile.record_unlock_key(index, &key_bytes);
+    configfile.record_unlocked(index);
+    configfile.reset_failed_attempts(index);
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!(
+            "Could not save updated config file into json: {}",
+            e
+        ));
+    }
+
+    Ok(checks
+        .into_iter()
+        .map(|(name, checksum)| RestoredFileViewModel::new(name, checksum))
+        .collect())
+}
+
+// List the files in a locked vault. Nothing is decrypted to disk and the vault stays locked.
+// Like unlocking, a password that opens nothing counts as a failed attempt.
+#[tauri::command]
+pub fn list_vault_contents(
+    id: &str,
+    password: Password,
+    handle: tauri::AppHandle,
+) -> Result<Vec<VaultFileViewModel>, String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    if !configfile.index_locked(index) {
+        return Err("The vault is unlocked, its files are in its directory.".to_string());
+    }
+    check_vault_state(&configfile, index)?;
+
+    let (opened, _) = open_locked_vault(&mut configfile, index, &password, handle)?;
+
+    Ok(opened
+        .payload
+        .manifest
+        .files
+        .iter()
+        .map(VaultFileViewModel::new)
+        .collect())
+}
+
+// Check that a locked vault can be restored: its slot header, manifest and every chunk are
+// authenticated with the password and every file is compared with its hash. Nothing is written
+// to disk. An intact vault has the time recorded as its last verification.
+// With remember, the key is kept for this session so scrubbing can check the files as well.
+// Without it, a key kept before is forgotten.
+#[tauri::command]
+pub fn verify_vault(
+    id: &str,
+    password: Password,
+    remember: bool,
+    handle: tauri::AppHandle,
+) -> Result<VerifyReportViewModel, String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    if !configfile.index_locked(index) {
+        return Err("Only locked vaults can be verified.".to_string());
+    }
+    check_vault_state(&configfile, index)?;
+
+    let (opened, key_bytes) = open_locked_vault(&mut configfile, index, &password, handle.clone())?;
+    let report = VerifyReportViewModel::new(&configfile.vaults[index], &opened.payload, &key_bytes);
+
+    let scrub_keys = handle.state::<ScrubKeys>();
+    let mut scrub_keys = scrub_keys.0.lock().unwrap();
+    if remember {
+        scrub_keys.insert(id.to_string(), Arc::new(key_bytes));
+    } else {
+        scrub_keys.remove(id);
+    }
+    drop(scrub_keys);
+
+    if report.is_intact() {
+        configfile.record_verified(index);
+        if let Err(e) = configfile.to_json(handle) {
+            return Err(format!("Error writing configfile back to json: {}", e));
+        }
+    }
+
+    Ok(report)
+}
+
+// Restore the named files of a locked vault into dest. The vault stays locked and the other
+// files are never decrypted. Files that are already in dest are not overwritten.
+// Returns how each restored file compares with the hash it was locked with.
+#[tauri::command]
+pub fn extract_from_vault(
+    id: &str,
+    password: Password,
+    names: Vec<String>,
+    dest: &str,
+    handle: tauri::AppHandle,
+) -> Result<Vec<RestoredFileViewModel>, String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    if !configfile.index_locked(index) {
+        return Err("The vault is unlocked, its files are in its directory.".to_string());
+    }
+    check_vault_state(&configfile, index)?;
+
+    let dest = canonical_path(dest)?;
+    if dest == configfile.get_path(index) {
+        return Err("The files must be extracted into another directory!".to_string());
+    }
+    let dest = path::Path::new(&dest);
+
+    let (opened, key_bytes) = open_locked_vault(&mut configfile, index, &password, handle)?;
+    let payload = &opened.payload;
+
+    // Check every name before writing anything.
+    let mut files = vec![];
+    for name in &names {
+        let file = payload
+            .file(name)
+            .ok_or_else(|| format!("The vault has no file named {}!", name))?;
+        if dest.join(&file.name).exists() {
+            return Err(format!("{} already exists in the destination!", file.name));
+        }
+        files.push(file);
+    }
+
+    let mut checks = vec![];
+    for file in files {
+        let checksum = restore_file(payload, file, &key_bytes, dest)?;
+        checks.push(RestoredFileViewModel::new(file.name.to_string(), checksum));
+    }
+
+    Ok(checks)
+}
+
+// Encrypt the files at paths into a locked vault without unlocking it. A file of the same name
+// in the vault is replaced. The files at paths are left in place.
+#[tauri::command]
+pub fn add_to_vault(
+    id: &str,
+    password: Password,
+    paths: Vec<String>,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    edit_locked_vault(id, &password, handle, |payload, key| {
+        for path in &paths {
+            let path = path::Path::new(path);
+            if path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(is_reserved_name)
+            {
+                return Err(format!(
+                    "{} has a name that is never locked into a vault!",
+                    path.display()
+                ));
+            }
+            add_path(payload, path, key)?;
+        }
+
+        Ok(())
+    })
+}
+
+// Remove the named files from a locked vault. Their chunks are dropped from the vaultfile.
+#[tauri::command]
+pub fn remove_from_vault(
+    id: &str,
+    password: Password,
+    names: Vec<String>,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    edit_locked_vault(id, &password, handle, |payload, _| {
+        for name in &names {
+            if !payload.remove_file(name) {
+                return Err(format!("The vault has no file named {}!", name));
+            }
+        }
+
+        Ok(())
+    })
+}
+
+// Overwrite the named file in a locked vault with the contents of the file at path. It keeps
+// its name in the vault.
+#[tauri::command]
+pub fn replace_in_vault(
+    id: &str,
+    password: Password,
+    name: &str,
+    path: &str,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    edit_locked_vault(id, &password, handle, |payload, key| {
+        if payload.file(name).is_none() {
+            return Err(format!("The vault has no file named {}!", name));
+        }
+        if is_reserved_name(name) {
+            return Err(format!(
+                "{} is a name that is never locked into a vault!",
+                name
+            ));
+        }
+        add_path_as(payload, name, path::Path::new(path), key)
+    })
+}
+
+// Set how many consecutive failed unlock attempts wipe the vault. 0 turns wiping off.
+#[tauri::command]
+pub fn set_wipe_after(
+    id: &str,
+    password: Password,
+    attempts: u32,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
 
-    // Variables
     let argon2 = Argon2::default();
-    let index = configfile.index_of_path(&path);
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+
     let hash = configfile.get_hash(index);
-    let salt = configfile.get_salt(index);
+    if !verify_password(&argon2, hash.to_string(), &password) {
+        return Err("Incorrect password!".to_string());
+    }
 
-    // Verify password
-    if !verify_password(&argon2, hash.to_string(), password) {
+    configfile.set_wipe_after(index, attempts);
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Set the zstd level the files of the vault are compressed with before they are encrypted.
+// 0 turns compression off. It applies to files encrypted from now on, the files already in the
+// vault are read the way they were stored.
+#[tauri::command]
+pub fn set_compression_level(
+    id: &str,
+    password: Password,
+    level: i32,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let argon2 = Argon2::default();
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+
+    let hash = configfile.get_hash(index);
+    if !verify_password(&argon2, hash.to_string(), &password) {
         return Err("Incorrect password!".to_string());
     }
 
-    // Derive the key
-    let mut key_bytes = [0u8; 32];
-    derive_key(argon2, password, salt, &mut key_bytes);
+    let levels = zstd::compression_level_range();
+    if level != 0 && !levels.contains(&level) {
+        return Err(format!(
+            "The compression level must be 0 or between {} and {}!",
+            levels.start(),
+            levels.end()
+        ));
+    }
 
-    let path_p = path::Path::new(path);
-    let vaultfile_bytes = match fs::read(format!("{}/vaultfile", path_p.to_str().unwrap())) {
-        Ok(vaultfile_bytes) => vaultfile_bytes,
-        Err(e) => return Err(e.to_string()),
-    };
+    configfile.set_compression_level(index, level);
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_scrub_interval(handle: tauri::AppHandle) -> Result<u64, String> {
+    let configfile =
+        Config::from_json(handle).map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    Ok(configfile.scrub_interval)
+}
+
+// Set the percentage of the vaultfile that its parity can rebuild. 0 removes the parity.
+// The parity of a locked vault is renewed right away, unless its vaultfile needs repairing.
+#[tauri::command]
+pub fn set_parity_redundancy(
+    id: &str,
+    password: Password,
+    percent: u32,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let argon2 = Argon2::default();
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+
+    let hash = configfile.get_hash(index);
+    if !verify_password(&argon2, hash.to_string(), &password) {
+        return Err("Incorrect password!".to_string());
+    }
+
+    if percent > 100 {
+        return Err("The redundancy must be between 0 and 100 percent!".to_string());
+    }
+
+    if configfile.index_locked(index) {
+        check_vault_state(&configfile, index)?;
+        let path = path::Path::new(configfile.get_path(index));
+        if parity::damaged_shards(path)? > 0 {
+            return Err("The vaultfile is damaged, repair it first!".to_string());
+        }
+
+        let bytes = volume::read_vaultfile(path)?;
+        parity::write_parity(path, &bytes, percent)?;
+    }
+
+    configfile.set_parity_redundancy(index, percent);
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Split the vaultfile into volumes of size bytes, so it can be moved on media or through
+// services that limit the size of a file. 0 keeps it in one file. The vaultfile of a locked
+// vault is split again right away, unless it needs repairing.
+#[tauri::command]
+pub fn set_volume_size(
+    id: &str,
+    password: Password,
+    size: u64,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
 
-    let plaintext_bytes = decrypt_file(vaultfile_bytes, &key_bytes);
-    reconstruct_files(plaintext_bytes, &path_p)?;
+    let argon2 = Argon2::default();
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
 
-    // Remove the vaultfile after decryption
-    if let Err(e) = fs::remove_file(format!("{}/vaultfile", path_p.to_str().unwrap())) {
+    let hash = configfile.get_hash(index);
+    if !verify_password(&argon2, hash.to_string(), &password) {
+        return Err("Incorrect password!".to_string());
+    }
+
+    if size != 0 && size < MIN_VOLUME_SIZE {
         return Err(format!(
-            "Error removing vaultfile after decryption: {}",
-            e.to_string()
+            "Volumes must be at least {} bytes!",
+            MIN_VOLUME_SIZE
         ));
     }
 
-    // Mark the path unlocked in and save the config
-    configfile.mark_unlocked(index);
+    configfile.set_volume_size(index, size);
+    if configfile.index_locked(index) {
+        check_vault_state(&configfile, index)?;
+        let path = path::Path::new(configfile.get_path(index));
+        if parity::damaged_shards(path)? > 0 {
+            return Err("The vaultfile is damaged, repair it first!".to_string());
+        }
+
+        rewrite_vaultfile(path, configfile.vaults[index].storage())?;
+        configfile.record_header_mac(index);
+    }
+
     if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Rebuild the damaged parts of the vaultfile of a locked vault from its parity. No password is
+// needed, the parity is over the ciphertext.
+// Returns how many pieces of the vaultfile and its parity were rebuilt.
+#[tauri::command]
+pub fn repair_vault(id: &str, handle: tauri::AppHandle) -> Result<usize, String> {
+    let configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    if !configfile.index_locked(index) {
+        return Err("Only locked vaults can be repaired.".to_string());
+    }
+
+    let path = path::Path::new(configfile.get_path(index));
+    if !path.is_dir() {
+        return Err(VaultStatus::Missing.problem().unwrap().to_string());
+    }
+
+    parity::repair(path, configfile.vaults[index].volume_size)
+}
+
+// Set how many hours pass between background checks of the locked vaults. 0 turns them off.
+#[tauri::command]
+pub fn set_scrub_interval(hours: u64, handle: tauri::AppHandle) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    if hours > MAX_SCRUB_INTERVAL {
         return Err(format!(
-            "Could not save updated config file into json: {}",
-            e
+            "The interval can be at most {} hours!",
+            MAX_SCRUB_INTERVAL
         ));
     }
 
+    configfile.set_scrub_interval(hours);
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Check the locked vaults now instead of waiting for the interval. Damaged vaults are also
+// reported with events, like background checks do.
+#[tauri::command]
+pub fn scrub_now(handle: tauri::AppHandle) -> Result<Vec<DamagedVaultViewModel>, String> {
+    let configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    Ok(scrub_vaults(&configfile, &handle))
+}
+
+// Put the files of decoy_path into a locked vault as a decoy. Unlocking the vault with
+// decoy_password restores them instead of the vault's own files.
+#[tauri::command]
+pub fn set_decoy(
+    id: &str,
+    password: Password,
+    decoy_password: Password,
+    decoy_path: &str,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let (key_bytes, mut configfile, index) = locked_vault_key(id, &password, handle.clone())?;
+    let metadata_key = configfile.metadata_key(index);
+    let vault = configfile.vaults[index].clone();
+    check_decoy(&vault.path, &password, &decoy_password, decoy_path)?;
+
+    let decoy_key = derive_key(Argon2::default(), &decoy_password, &vault.salt);
+    let payload = encrypt_directory(decoy_path, &decoy_key, vault.compression_level)?;
+
+    set_decoy_util(
+        &vault.path,
+        &key_bytes,
+        &decoy_key,
+        &payload,
+        &configfile.vault_info(index),
+        &metadata_key,
+        vault.storage(),
+    )?;
+    configfile.record_header_mac(index);
+
+    // The metadata key may be new.
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Put the files of hidden_path into the padding of a locked vault as a hidden vault.
+// Unlocking the vault with hidden_password restores them. The vaultfile looks the same with or
+// without a hidden vault, so it is only kept by giving hidden_password when locking the vault.
+#[tauri::command]
+pub fn create_hidden_vault(
+    id: &str,
+    password: Password,
+    hidden_password: Password,
+    hidden_path: &str,
+    handle: tauri::AppHandle,
+) -> Result<(), String> {
+    if hidden_password == password {
+        return Err("The hidden password must differ from the vault password!".to_string());
+    }
+    let (key_bytes, mut configfile, index) = locked_vault_key(id, &password, handle.clone())?;
+    let vault = &configfile.vaults[index];
+    if canonical_path(hidden_path)? == vault.path {
+        return Err("The hidden files must come from another directory!".to_string());
+    }
+
+    let hidden_key = derive_key(Argon2::default(), &hidden_password, &vault.salt);
+
+    set_hidden_util(
+        &vault.path,
+        &key_bytes,
+        &hidden_key,
+        hidden_path,
+        vault.compression_level,
+        vault.storage(),
+    )?;
+    configfile.record_header_mac(index);
+
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Replace the decoy of a locked vault with random filler.
+#[tauri::command]
+pub fn remove_decoy(id: &str, password: Password, handle: tauri::AppHandle) -> Result<(), String> {
+    let (key_bytes, mut configfile, index) = locked_vault_key(id, &password, handle.clone())?;
+    let metadata_key = configfile.metadata_key(index);
+
+    remove_decoy_util(
+        configfile.get_path(index),
+        &key_bytes,
+        &configfile.vault_info(index),
+        &metadata_key,
+        configfile.vaults[index].storage(),
+    )?;
+    configfile.record_header_mac(index);
+
+    // The metadata key may be new.
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
+    Ok(())
+}
+
+// Open a locked vault with the password of the vault, its decoy or its hidden vault.
+// Returns the key it was opened with.
+// Only consecutive failed attempts count, so any successful open resets them.
+fn open_locked_vault(
+    configfile: &mut Config,
+    index: usize,
+    password: &str,
+    handle: tauri::AppHandle,
+) -> Result<(OpenedVault, SecretKey), String> {
+    let argon2 = Argon2::default();
+    let path = configfile.get_path(index).to_string();
+    let is_vault_password =
+        verify_password(&argon2, configfile.get_hash(index).to_string(), password);
+    let key_bytes = derive_key(argon2, password, configfile.get_salt(index));
+
+    let seed = configfile.vaults[index].seed;
+    match OpenedVault::open(path::Path::new(&path), &key_bytes, seed)? {
+        Some(opened) => {
+            if configfile.vaults[index].failed_attempts != 0 {
+                configfile.reset_failed_attempts(index);
+                if let Err(e) = configfile.to_json(handle) {
+                    return Err(format!("Could not reset the failed attempts: {}", e));
+                }
+            }
+            Ok((opened, key_bytes))
+        }
+        None if is_vault_password => Err("Could not decrypt the vaultfile!".to_string()),
+        None => Err(fail_unlock_attempt(configfile, index, &path, handle)),
+    }
+}
+
+// Open a locked vault, change what the password opens with edit and write it back.
+fn edit_locked_vault(
+    id: &str,
+    password: &str,
+    handle: tauri::AppHandle,
+    edit: impl FnOnce(&mut Payload, &[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut configfile = Config::from_json(handle.clone())
+        .map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    if !configfile.index_locked(index) {
+        return Err("The vault is unlocked, change the files in its directory.".to_string());
+    }
+    check_vault_state(&configfile, index)?;
+
+    let (mut opened, key_bytes) =
+        open_locked_vault(&mut configfile, index, password, handle.clone())?;
+    opened.payload.compression_level = configfile.vaults[index].compression_level;
+    edit(&mut opened.payload, &key_bytes)?;
+
+    let metadata_key = configfile.metadata_key(index);
+    let path = path::Path::new(configfile.get_path(index));
+    opened.save(
+        path,
+        &key_bytes,
+        &configfile.vault_info(index),
+        Some(&metadata_key),
+        configfile.vaults[index].storage(),
+    )?;
+    configfile.record_header_mac(index);
+
+    // The metadata key may be new.
+    if let Err(e) = configfile.to_json(handle) {
+        return Err(format!("Error writing configfile back to json: {}", e));
+    }
+
     Ok(())
 }
+
+// Drop the key kept for scrubbing a vault, if there is one.
+fn forget_scrub_key(id: &str, handle: &tauri::AppHandle) {
+    let scrub_keys = handle.state::<ScrubKeys>();
+    scrub_keys.0.lock().unwrap().remove(id);
+}
+
+// Verify the password of a locked vault and return its key, with the configfile and the index
+// of the vault in it.
+fn locked_vault_key(
+    id: &str,
+    password: &str,
+    handle: tauri::AppHandle,
+) -> Result<(SecretKey, Config, usize), String> {
+    let configfile =
+        Config::from_json(handle).map_err(|e| format!("Could not read the configfile: {}", e))?;
+
+    let argon2 = Argon2::default();
+    let index = configfile.index_of_id(id)?;
+    if configfile.index_wiped(index) {
+        return Err("This vault has been wiped!".to_string());
+    }
+    if !configfile.index_locked(index) {
+        return Err("The vault must be locked!".to_string());
+    }
+    check_vault_state(&configfile, index)?;
+
+    let hash = configfile.get_hash(index);
+    let salt = configfile.get_salt(index);
+    if !verify_password(&argon2, hash.to_string(), password) {
+        return Err("Incorrect password!".to_string());
+    }
+
+    let key_bytes = derive_key(argon2, password, salt);
+
+    Ok((key_bytes, configfile, index))
+}
+
+// Add the vault in the directory to the configfile, unless it is already there.
+// The password must open the vault. The vaultfile cannot tell the vault's own password from its
+// decoy password, so the password given is taken to be the vault's.
+fn import_into(
+    configfile: &mut Config,
+    path: &path::Path,
+    name: &str,
+    password: &str,
+) -> Result<(), String> {
+    let (path, info, is_locked) = new_vault_in(configfile, path)?;
+
+    let argon2 = Argon2::default();
+    let key_bytes = derive_key(argon2.clone(), password, &info.salt);
+    if !vault_slot_opens(path::Path::new(&path), &key_bytes) {
+        return Err("Incorrect password!".to_string());
+    }
+    let hash = hash_with_salt(&argon2, password, &info.salt)?;
+
+    configfile.append_new(&path, name, &hash, &info, is_locked);
+
+    Ok(())
+}
+
+// Read the vault in a directory that is not in the configfile yet. Returns the canonical path
+// of the directory, the description of the vault and whether it is locked.
+fn new_vault_in(
+    configfile: &Config,
+    path: &path::Path,
+) -> Result<(String, VaultInfo, bool), String> {
+    let (info, is_locked) = read_vault_info(path)?;
+    let path = canonical_path(path.to_str().ok_or("The path is not valid utf8!")?)?;
+
+    if configfile.id_exists(&info.id) || configfile.path_exists(&path) {
+        return Err("This vault is already added!".to_string());
+    }
+
+    Ok((path, info, is_locked))
+}
+
+// Refuse to touch a vault whose directory does not match the configfile.
+fn check_vault_state(configfile: &Config, index: usize) -> Result<(), String> {
+    let missing = volume::missing_volumes(path::Path::new(configfile.get_path(index)));
+    if !missing.is_empty() {
+        return Err(volume::missing_volumes_error(&missing));
+    }
+
+    match VaultStatus::of(&configfile.vaults[index]).problem() {
+        Some(problem) => Err(problem.to_string()),
+        None => Ok(()),
+    }
+}
+
+fn check_decoy(
+    path: &str,
+    password: &str,
+    decoy_password: &str,
+    decoy_path: &str,
+) -> Result<(), String> {
+    if decoy_password == password {
+        return Err("The decoy password must differ from the vault password!".to_string());
+    }
+    if canonical_path(decoy_path)? == path {
+        return Err("The decoy files must come from another directory!".to_string());
+    }
+
+    Ok(())
+}
+
+// Count a failed unlock attempt and wipe the vault if it crossed its limit.
+// Wiping destroys the vaultfile and forgets the salt and hash in the configfile. There are no
+// recovery keys, so a wiped vault cannot be brought back. A vault whose vaultfile could not be
+// destroyed is not marked wiped, so the next failed attempt tries again.
+// Returns the error to report to the frontend.
+fn fail_unlock_attempt(
+    configfile: &mut Config,
+    index: usize,
+    path: &str,
+    handle: tauri::AppHandle,
+) -> String {
+    let should_wipe = configfile.record_failed_attempt(index);
+    let mut message = "Incorrect password!".to_string();
+
+    if should_wipe {
+        match destroy_vaultfiles(path::Path::new(path)) {
+            Ok(()) => {
+                configfile.mark_wiped(index);
+                message = "Too many failed attempts. The vault has been wiped!".to_string();
+            }
+            Err(e) => {
+                println!("Error deleting the vaultfile of a wiped vault: {}", e);
+                message = format!(
+                    "Too many failed attempts, but the vault could not be wiped: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    if let Err(e) = configfile.to_json(handle) {
+        return format!("Error writing configfile back to json: {}", e);
+    }
+
+    message
+}
+
+// Securely delete every file a vault is kept in. The files with a header go first, the salt in
+// it is all the key needs besides the password. Parity with enough redundancy rebuilds the
+// vaultfile on its own, so it goes too. Files that fail do not stop the others from being
+// deleted.
+fn destroy_vaultfiles(path: &path::Path) -> Result<(), String> {
+    let mut files = vec![volume::header_path(path), path.join(RETAINED_VAULTFILE)];
+    files.extend(volume::vaultfile_paths(path));
+    files.push(path.join(PARITY_FILE));
+
+    let mut errors = vec![];
+    for file in files {
+        if file.exists() {
+            if let Err(e) = secure_delete(&file) {
+                errors.push(e);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(" "))
+    }
+}
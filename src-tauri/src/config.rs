@@ -1,118 +1,851 @@
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        SaltString,
+    },
+    Argon2,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, File},
-    io::{self, Write},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
     vec::Vec,
 };
 use tauri::Manager;
+use zeroize::Zeroizing;
+
+use crate::{
+    secret::SecretKey,
+    utils::{
+        calculate_seed, decrypt_file, derive_key, encrypt_file, key_fingerprint, unix_now,
+        write_atomic, Storage,
+    },
+    vaultfile::{self, VaultInfo},
+};
 
-#[derive(Serialize, Deserialize, Default)]
+// Bump this when the layout of the configfile changes and teach Config::parse to migrate.
+pub const SCHEMA_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
 pub struct Config {
-    pub paths: Vec<String>,
-    pub names: Vec<String>,
-    pub hashes: Vec<String>,
+    pub schema_version: u32,
+    pub vaults: Vec<VaultEntry>,
+
+    // Hours between background checks of the locked vaults. 0 turns them off.
+    #[serde(default)]
+    pub scrub_interval: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VaultEntry {
+    pub id: String,
+    pub path: String,
+    pub name: String,
+    pub hash: String,
 
     // The salts are held in the configfile. This is a security vulnerability.
     // In a real life scenario the salt should be stored more securely.
-    pub salts: Vec<String>,
-    pub is_locked: Vec<bool>,
+    pub salt: String,
+    pub is_locked: bool,
+
+    // Seeds the shuffle of the vault's bytes. It used to come from the path, which kept
+    // vaults from being moved, so it is kept here instead.
+    pub seed: u64,
+
+    // Wipe the vault after this many consecutive failed unlock attempts. 0 means never.
+    pub wipe_after: u32,
+    pub failed_attempts: u32,
+    pub is_wiped: bool,
+
+    // Seconds since the unix epoch. Missing in configfiles written before they were recorded,
+    // and the creation time of imported vaults is not known.
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub last_locked: Option<u64>,
+    #[serde(default)]
+    pub last_unlocked: Option<u64>,
+    #[serde(default)]
+    pub last_failed_attempt: Option<u64>,
+    #[serde(default)]
+    pub last_verified: Option<u64>,
+
+    // Key of the metadata in the vaultfile, hex encoded. Empty until the vault is first locked
+    // by a version of the app that writes metadata.
+    #[serde(default)]
+    pub metadata_key: String,
+
+    // zstd level the files are compressed with before they are encrypted. 0 means they are
+    // stored as they are.
+    #[serde(default)]
+    pub compression_level: i32,
+
+    // Percentage of the vaultfile that its parity can rebuild. 0 means it has none.
+    #[serde(default)]
+    pub parity_redundancy: u32,
+
+    // Size in bytes of the volumes the vaultfile is split into. 0 keeps it in one file.
+    #[serde(default)]
+    pub volume_size: u64,
+
+    // Whether the header of the vaultfile has been written with a MAC. From then on a header
+    // without one has been tampered with.
+    #[serde(default)]
+    pub header_mac: bool,
+
+    // Fingerprint of the key the vault was unlocked with. Locking with another key would put
+    // the files into the wrong part of the vaultfile, such as the files of the decoy over the
+    // vault. None while the vault is locked, or if it was unlocked outside the app.
+    #[serde(default)]
+    pub unlocked_with: Option<String>,
+}
+
+// The configfile before schema versions, which kept every field in its own vector.
+#[derive(Deserialize)]
+struct LegacyConfig {
+    paths: Vec<String>,
+    names: Vec<String>,
+    hashes: Vec<String>,
+    salts: Vec<String>,
+    is_locked: Vec<bool>,
+    #[serde(default)]
+    wipe_after: Vec<u32>,
+    #[serde(default)]
+    failed_attempts: Vec<u32>,
+    #[serde(default)]
+    is_wiped: Vec<bool>,
+    #[serde(default)]
+    unlocked_with: Vec<Option<String>>,
+}
+
+// How many earlier versions of the configfile are kept next to it.
+const BACKUP_COUNT: usize = 3;
+
+// Messages for the user about things that happened while the app started.
+#[derive(Default)]
+pub struct StartupNotices(pub Mutex<Vec<String>>);
+
+// The key of an encrypted configfile and the salt it was derived with.
+// Empty until the user enters the master password, or if there is none.
+#[derive(Default)]
+pub struct MasterKey(pub Mutex<Option<(SecretKey, String)>>);
+
+// An encrypted configfile. The config json is encrypted with a key derived from the
+// master password and the salt.
+#[derive(Serialize, Deserialize)]
+struct EncryptedConfig {
+    salt: String,
+    ciphertext: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            schema_version: SCHEMA_VERSION,
+            vaults: vec![],
+            scrub_interval: 0,
+        }
+    }
+}
+
+impl VaultEntry {
+    pub fn new(id: &str, path: &str, name: &str, hash: &str, salt: &str, is_locked: bool) -> Self {
+        VaultEntry {
+            id: id.to_string(),
+            path: path.to_string(),
+            name: name.to_string(),
+            hash: hash.to_string(),
+            salt: salt.to_string(),
+            is_locked,
+            seed: calculate_seed(path),
+            wipe_after: 0,
+            failed_attempts: 0,
+            is_wiped: false,
+            created_at: None,
+            last_locked: None,
+            last_unlocked: None,
+            last_failed_attempt: None,
+            last_verified: None,
+            metadata_key: String::new(),
+            compression_level: 0,
+            parity_redundancy: 0,
+            volume_size: 0,
+            header_mac: false,
+            unlocked_with: None,
+        }
+    }
+
+    pub fn storage(&self) -> Storage {
+        let metadata_key = Zeroizing::new(hex::decode(&self.metadata_key).unwrap_or_default());
+        Storage {
+            parity_redundancy: self.parity_redundancy,
+            volume_size: self.volume_size,
+            header_key: (metadata_key.len() == 32).then(|| vaultfile::header_key(&metadata_key)),
+            header_mac: self.header_mac,
+        }
+    }
+}
+
+impl From<LegacyConfig> for Config {
+    fn from(legacy: LegacyConfig) -> Self {
+        let vaults = (0..legacy.paths.len())
+            .filter_map(|i| {
+                // Drop entries the vectors lost track of rather than guessing their fields.
+                let mut entry = VaultEntry::new(
+                    &new_vault_id(),
+                    &legacy.paths[i],
+                    legacy.names.get(i)?,
+                    legacy.hashes.get(i)?,
+                    legacy.salts.get(i)?,
+                    *legacy.is_locked.get(i)?,
+                );
+                entry.wipe_after = legacy.wipe_after.get(i).copied().unwrap_or(0);
+                entry.failed_attempts = legacy.failed_attempts.get(i).copied().unwrap_or(0);
+                entry.is_wiped = legacy.is_wiped.get(i).copied().unwrap_or(false);
+                entry.unlocked_with = legacy.unlocked_with.get(i).cloned().flatten();
+                Some(entry)
+            })
+            .collect();
+
+        Config {
+            schema_version: SCHEMA_VERSION,
+            vaults,
+            scrub_interval: 0,
+        }
+    }
 }
 
 impl Config {
     // Parses the configfile into Config object
+    // Fails while the configfile is encrypted and the master password was not entered yet.
     pub fn from_json(handle: tauri::AppHandle) -> io::Result<Self> {
-        let mut data_dir = handle
-            .path()
-            .app_data_dir()
-            .expect("The app data directory cannot be found.");
-        data_dir.push("config");
+        let json_str = read_configfile(&handle)?;
 
-        let encoded = fs::read_to_string(&data_dir)
-            .expect("Error reading json file contents from app data dir into json string");
-        let json_str = String::from_utf8(hex::decode(encoded).unwrap()).unwrap();
+        match Config::parse(&json_str) {
+            Ok((result, _)) => Ok(result),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
 
-        let result: Config =
-            serde_json::from_str(&json_str).expect("Failed parsing configfile as a Config struct.");
+    // Parse a configfile of any schema version into the current one.
+    // Also returns whether it had to be migrated.
+    pub fn parse(json_str: &str) -> Result<(Self, bool), String> {
+        let value: serde_json::Value = match serde_json::from_str(json_str) {
+            Ok(value) => value,
+            Err(e) => return Err(format!("The configfile is not valid json: {}", e)),
+        };
 
-        Ok(result)
+        let version = match value.get("schema_version") {
+            Some(version) => version.as_u64().unwrap_or(0),
+            None => 0,
+        };
+
+        match version {
+            0 => match serde_json::from_value::<LegacyConfig>(value) {
+                Ok(legacy) => Ok((legacy.into(), true)),
+                Err(e) => Err(format!("Could not parse the old configfile: {}", e)),
+            },
+            // Version 1 had no vault ids and version 2 took the seed from the path.
+            1 | 2 => {
+                let mut value = value;
+                value["schema_version"] = SCHEMA_VERSION.into();
+                if let Some(vaults) = value["vaults"].as_array_mut() {
+                    for vault in vaults {
+                        if version < 2 {
+                            vault["id"] = new_vault_id().into();
+                        }
+                        let path = vault["path"].as_str().unwrap_or_default();
+                        vault["seed"] = calculate_seed(path).into();
+                    }
+                }
+                match serde_json::from_value(value) {
+                    Ok(config) => Ok((config, true)),
+                    Err(e) => Err(format!("Could not parse the configfile: {}", e)),
+                }
+            }
+            v if v == SCHEMA_VERSION as u64 => match serde_json::from_value(value) {
+                Ok(config) => Ok((config, false)),
+                Err(e) => Err(format!("Could not parse the configfile: {}", e)),
+            },
+            v => Err(format!(
+                "The configfile has schema version {} but this app only knows up to {}.",
+                v, SCHEMA_VERSION
+            )),
+        }
     }
 
     // Converts from a Config object into into json file
+    // The configfile is encrypted if a master password is set.
     pub fn to_json(&self, handle: tauri::AppHandle) -> io::Result<()> {
-        let mut data_dir = handle
-            .path()
-            .app_data_dir()
-            .expect("The app data directory cannot be found.");
-        data_dir.push("config");
-
-        let json_str =
-            serde_json::to_string(self).expect("Error converting data file back to json string.");
-        let encoded = hex::encode(json_str);
+        let json_str = Zeroizing::new(
+            serde_json::to_string(self).expect("Error converting data file back to json string."),
+        );
 
-        let mut configfile =
-            File::create(data_dir).expect("Error opening meta file from the app data directory.");
+        let master_key = handle.state::<MasterKey>();
+        let master_key = master_key.0.lock().unwrap();
+        let contents = match master_key.as_ref() {
+            Some((key, salt)) => encrypt_configfile(&json_str, key, salt),
+            None => hex::encode(json_str.as_bytes()),
+        };
 
-        configfile
-            .write_all(encoded.as_bytes())
-            .expect("Error writing json string to meta file.");
-
-        Ok(())
+        let path = configfile_path(&handle)?;
+        backup_configfile(&path, master_key.as_ref().map(|(_, salt)| salt.as_str()))?;
+        write_atomic(&path, contents.as_bytes())
     }
 
     // Remove an entry at a given index
     pub fn remove_index(&mut self, index: usize) {
-        self.paths.remove(index);
-        self.names.remove(index);
-        self.hashes.remove(index);
-        self.salts.remove(index);
-        self.is_locked.remove(index);
+        self.vaults.remove(index);
     }
 
     // Append new vault to the file based on the given parameters.
-    pub fn append_new(&mut self, path: &str, name: &str, hash: &str, salt: &str, is_locked: bool) {
-        self.paths.push(path.to_string());
-        self.names.push(name.to_string());
-        self.hashes.push(hash.to_string());
-        self.salts.push(salt.to_string());
-        self.is_locked.push(is_locked);
-    }
-
-    // Returns the index of the entry of the given path
-    pub fn index_of_path(&self, path: &str) -> usize {
-        let index = self
-            .paths
+    pub fn append_new(
+        &mut self,
+        path: &str,
+        name: &str,
+        hash: &str,
+        info: &VaultInfo,
+        is_locked: bool,
+    ) {
+        let mut entry = VaultEntry::new(&info.id, path, name, hash, &info.salt, is_locked);
+        if let Some(seed) = info.seed {
+            entry.seed = seed;
+        }
+        self.vaults.push(entry);
+    }
+
+    // Returns true if a vault with the given id is in the configfile.
+    pub fn id_exists(&self, id: &str) -> bool {
+        self.vaults.iter().any(|vault| vault.id == id)
+    }
+
+    // The description of a vault that is written into its vaultfile.
+    pub fn vault_info(&self, index: usize) -> VaultInfo {
+        let vault = &self.vaults[index];
+        VaultInfo {
+            id: vault.id.clone(),
+            salt: vault.salt.clone(),
+            seed: Some(vault.seed),
+        }
+    }
+
+    // Point a vault at the directory it was moved to.
+    pub fn set_path(&mut self, index: usize, path: &str) {
+        self.vaults[index].path = path.to_string();
+    }
+
+    // Returns the index of the entry of the given vault id
+    pub fn index_of_id(&self, id: &str) -> Result<usize, String> {
+        self.vaults
             .iter()
-            .position(|p| p == path)
-            .expect("Could not find the specified path in metafile!");
-        index
+            .position(|v| v.id == id)
+            .ok_or_else(|| "Could not find the vault in the configfile!".to_string())
+    }
+
+    pub fn get_path(&self, index: usize) -> &str {
+        &self.vaults[index].path
     }
 
     // Returns true if a path is in the config.
     pub fn path_exists(&self, path: &str) -> bool {
-        self.paths.contains(&path.to_string())
+        self.vaults.iter().any(|v| v.path == path)
     }
 
     pub fn index_locked(&self, index: usize) -> bool {
-        self.is_locked[index]
+        self.vaults[index].is_locked
     }
 
     pub fn mark_unlocked(&mut self, index: usize) {
-        self.is_locked[index] = false;
+        self.vaults[index].is_locked = false;
     }
 
     pub fn mark_locked(&mut self, index: usize) {
-        self.is_locked[index] = true;
+        self.vaults[index].is_locked = true;
+        self.vaults[index].unlocked_with = None;
+    }
+
+    // Remember the key a vault was unlocked with, only it may lock the vault again.
+    pub fn record_unlock_key(&mut self, index: usize, key: &[u8]) {
+        self.vaults[index].unlocked_with = Some(key_fingerprint(key));
+    }
+
+    // Returns true if the vault was unlocked with a key other than the given one.
+    pub fn unlocked_with_other_key(&self, index: usize, key: &[u8]) -> bool {
+        self.vaults[index]
+            .unlocked_with
+            .as_ref()
+            .is_some_and(|fingerprint| *fingerprint != key_fingerprint(key))
+    }
+
+    // The key of the metadata in the vault's vaultfile. A vault without one gets a new one.
+    pub fn metadata_key(&mut self, index: usize) -> Zeroizing<Vec<u8>> {
+        let vault = &mut self.vaults[index];
+        if vault.metadata_key.is_empty() {
+            vault.metadata_key = new_metadata_key();
+        }
+        Zeroizing::new(hex::decode(&vault.metadata_key).unwrap_or_default())
+    }
+
+    // Remember that the vaultfile of a vault was written with a MAC over its header.
+    pub fn record_header_mac(&mut self, index: usize) {
+        let vault = &mut self.vaults[index];
+        vault.header_mac = vault.header_mac || vault.storage().header_key.is_some();
+    }
+
+    pub fn record_created(&mut self, index: usize) {
+        let now = unix_now();
+        self.vaults[index].created_at = Some(now);
+        self.vaults[index].last_locked = Some(now);
+    }
+
+    pub fn record_locked(&mut self, index: usize) {
+        self.vaults[index].last_locked = Some(unix_now());
+    }
+
+    pub fn record_unlocked(&mut self, index: usize) {
+        self.vaults[index].last_unlocked = Some(unix_now());
+    }
+
+    pub fn record_verified(&mut self, index: usize) {
+        self.vaults[index].last_verified = Some(unix_now());
     }
 
     pub fn get_hash(&self, index: usize) -> &str {
-        self.hashes
+        &self
+            .vaults
             .get(index)
             .expect("Could not retrieve hash: index out of bounds!")
+            .hash
+    }
+
+    pub fn index_wiped(&self, index: usize) -> bool {
+        self.vaults[index].is_wiped
+    }
+
+    pub fn set_wipe_after(&mut self, index: usize, attempts: u32) {
+        self.vaults[index].wipe_after = attempts;
+    }
+
+    pub fn set_compression_level(&mut self, index: usize, level: i32) {
+        self.vaults[index].compression_level = level;
+    }
+
+    pub fn set_parity_redundancy(&mut self, index: usize, percent: u32) {
+        self.vaults[index].parity_redundancy = percent;
+    }
+
+    pub fn set_volume_size(&mut self, index: usize, size: u64) {
+        self.vaults[index].volume_size = size;
+    }
+
+    pub fn set_scrub_interval(&mut self, hours: u64) {
+        self.scrub_interval = hours;
+    }
+
+    // Count a failed unlock attempt. Returns true if the vault should now be wiped.
+    pub fn record_failed_attempt(&mut self, index: usize) -> bool {
+        let vault = &mut self.vaults[index];
+        vault.failed_attempts += 1;
+        vault.last_failed_attempt = Some(unix_now());
+        vault.wipe_after != 0 && vault.failed_attempts >= vault.wipe_after
+    }
+
+    pub fn reset_failed_attempts(&mut self, index: usize) {
+        self.vaults[index].failed_attempts = 0;
+    }
+
+    // Forget the key material of a wiped vault. The entry is kept so the user can see what happened.
+    pub fn mark_wiped(&mut self, index: usize) {
+        let vault = &mut self.vaults[index];
+        vault.hash.clear();
+        vault.salt.clear();
+        vault.is_wiped = true;
     }
 
     pub fn get_salt(&self, index: usize) -> &str {
-        self.salts
+        &self
+            .vaults
             .get(index)
             .expect("Could not retrieve salt: index out of bounds!")
+            .salt
+    }
+}
+
+// Returns true if the configfile is encrypted with a master password.
+pub fn is_encrypted(handle: &tauri::AppHandle) -> io::Result<bool> {
+    let contents = fs::read_to_string(configfile_path(handle)?)?;
+    Ok(parse_encrypted(&contents).is_some())
+}
+
+// Derive the key of an encrypted configfile from the master password and keep it for this
+// session. Fails if the password does not decrypt the configfile.
+// A configfile that does not decrypt while one of its backups does is damaged rather than
+// locked with another password. It is restored from that backup and the user is told so with a
+// startup notice.
+pub fn unlock_configfile(handle: &tauri::AppHandle, password: &str) -> Result<(), String> {
+    let path = configfile_path(handle).map_err(|e| e.to_string())?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => return Err(format!("Could not read the configfile: {}", e)),
+    };
+    let encrypted = parse_encrypted(&contents).ok_or("The configfile is not encrypted!")?;
+
+    let key = derive_key(Argon2::default(), password, &encrypted.salt);
+    if decrypt_configfile(&encrypted, &key).is_some() {
+        *handle.state::<MasterKey>().0.lock().unwrap() = Some((key, encrypted.salt));
+        migrate(handle);
+        return Ok(());
+    }
+
+    for i in 1..=BACKUP_COUNT {
+        let Ok(backup) = fs::read_to_string(backup_path(&path, i)) else {
+            continue;
+        };
+        let Some(backup_encrypted) = parse_encrypted(&backup) else {
+            continue;
+        };
+        let backup_key = derive_key(Argon2::default(), password, &backup_encrypted.salt);
+        if decrypt_configfile(&backup_encrypted, &backup_key).is_none() {
+            continue;
+        }
+
+        // Keep the damaged configfile around in case it can be repaired by hand.
+        let corrupted = path.with_file_name("config.corrupted");
+        if let Err(e) = fs::rename(&path, &corrupted) {
+            println!("Could not set the corrupted configfile aside: {}", e);
+        }
+        if let Err(e) = write_atomic(&path, backup.as_bytes()) {
+            return Err(format!(
+                "Could not restore the configfile from a backup: {}",
+                e
+            ));
+        }
+
+        let notice = format!(
+            "The configfile was damaged and has been restored from a backup. Changes made \
+             after that backup are missing. The damaged file was kept at {}.",
+            corrupted.display()
+        );
+        println!("{}", notice);
+        let notices = handle.state::<StartupNotices>();
+        notices.0.lock().unwrap().push(notice);
+
+        *handle.state::<MasterKey>().0.lock().unwrap() = Some((backup_key, backup_encrypted.salt));
+        migrate(handle);
+        return Ok(());
+    }
+
+    Err("Incorrect master password!".to_string())
+}
+
+// Bring a configfile written by an older version of the app to the current schema. A version 1
+// configfile would get new vault ids every time it is read until it is written again.
+// An encrypted configfile cannot be read at startup, it is migrated once the master password
+// was entered.
+pub fn migrate(handle: &tauri::AppHandle) {
+    let json_str = match read_configfile(handle) {
+        Ok(json_str) => json_str,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+        Err(e) => {
+            println!("Could not read the configfile to migrate it: {}", e);
+            return;
+        }
+    };
+
+    match Config::parse(&json_str) {
+        Ok((config, true)) => match config.to_json(handle.clone()) {
+            Ok(()) => println!("Migrated the configfile to the current schema."),
+            Err(e) => println!("Could not write the migrated configfile: {}", e),
+        },
+        Ok(_) => {}
+        Err(e) => println!("Could not migrate the configfile: {}", e),
+    }
+}
+
+// Encrypt the configfile with a new master password. A plain configfile gets encrypted for
+// the first time. An encrypted one must have been unlocked first.
+pub fn set_master_password(handle: &tauri::AppHandle, password: &str) -> Result<(), String> {
+    // Read with the current key, if any, before it is replaced.
+    let config = Config::from_json(handle.clone()).map_err(|e| e.to_string())?;
+
+    let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    let key = derive_key(Argon2::default(), password, salt.as_str());
+    let master_key = handle.state::<MasterKey>();
+    let old_key = master_key
+        .0
+        .lock()
+        .unwrap()
+        .replace((key, salt.as_str().to_string()));
+
+    if let Err(e) = config.to_json(handle.clone()) {
+        // The configfile on disk still uses the old key.
+        *master_key.0.lock().unwrap() = old_key;
+        return Err(format!("Could not write the encrypted configfile: {}", e));
+    }
+
+    // The backups would still open without the new master password.
+    let path = configfile_path(handle).map_err(|e| e.to_string())?;
+    let new_key = master_key.0.lock().unwrap();
+    let (key, salt) = new_key.as_ref().unwrap();
+    reencrypt_backups(&path, old_key.as_ref(), key, salt);
+
+    Ok(())
+}
+
+// Restore a corrupted configfile from its newest backup that can still be read.
+// Returns a message for the user if the configfile had to be replaced.
+pub fn recover_configfile(handle: &tauri::AppHandle) -> Option<String> {
+    let path = configfile_path(handle).ok()?;
+    match fs::read_to_string(&path) {
+        Ok(contents) if is_valid_configfile(&contents) => return None,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        _ => {}
+    }
+
+    // Keep the corrupted configfile around in case it can be repaired by hand.
+    let corrupted = path.with_file_name("config.corrupted");
+    if let Err(e) = fs::rename(&path, &corrupted) {
+        println!("Could not set the corrupted configfile aside: {}", e);
+    }
+
+    for i in 1..=BACKUP_COUNT {
+        let Ok(contents) = fs::read_to_string(backup_path(&path, i)) else {
+            continue;
+        };
+        if !is_valid_configfile(&contents) {
+            continue;
+        }
+
+        if let Err(e) = write_atomic(&path, contents.as_bytes()) {
+            println!("Could not restore the configfile from a backup: {}", e);
+            break;
+        }
+        return Some(format!(
+            "The configfile was corrupted and has been restored from a backup. Changes made \
+             after that backup are missing. The corrupted file was kept at {}.",
+            corrupted.display()
+        ));
+    }
+
+    Some(format!(
+        "The configfile was corrupted and no backup of it could be read, so the app starts with \
+         no vaults. The corrupted file was kept at {}.",
+        corrupted.display()
+    ))
+}
+
+pub fn new_metadata_key() -> String {
+    let mut key = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(key.as_mut());
+    hex::encode(key.as_ref())
+}
+
+pub fn new_vault_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn configfile_path(handle: &tauri::AppHandle) -> io::Result<PathBuf> {
+    match handle.path().app_data_dir() {
+        Ok(mut data_dir) => {
+            data_dir.push("config");
+            Ok(data_dir)
+        }
+        Err(e) => Err(io::Error::new(io::ErrorKind::NotFound, e.to_string())),
+    }
+}
+
+// Read the config json out of the configfile, decrypting it if needed.
+fn read_configfile(handle: &tauri::AppHandle) -> io::Result<Zeroizing<String>> {
+    let contents = fs::read_to_string(configfile_path(handle)?)?;
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let decoded = match parse_encrypted(&contents) {
+        Some(encrypted) => {
+            let master_key = handle.state::<MasterKey>();
+            let master_key = master_key.0.lock().unwrap();
+            let Some((key, _)) = master_key.as_ref() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "The configfile is locked with the master password.",
+                ));
+            };
+
+            let ciphertext =
+                hex::decode(&encrypted.ciphertext).map_err(|e| invalid(e.to_string()))?;
+            decrypt_file(&ciphertext, key).map_err(invalid)?.to_vec()
+        }
+        None => hex::decode(contents.trim()).map_err(|e| invalid(e.to_string()))?,
+    };
+
+    match String::from_utf8(decoded) {
+        Ok(json_str) => Ok(Zeroizing::new(json_str)),
+        Err(e) => Err(invalid(e.to_string())),
+    }
+}
+
+// Returns true if the configfile can be read. An encrypted configfile can only be checked for
+// its layout, its contents are checked once the master password is entered.
+fn is_valid_configfile(contents: &str) -> bool {
+    if let Some(encrypted) = parse_encrypted(contents) {
+        return hex::decode(&encrypted.ciphertext).is_ok();
+    }
+
+    match hex::decode(contents.trim()).map(String::from_utf8) {
+        Ok(Ok(json_str)) => Config::parse(&json_str).is_ok(),
+        _ => false,
+    }
+}
+
+fn backup_path(path: &Path, index: usize) -> PathBuf {
+    path.with_file_name(format!("config.{}.bak", index))
+}
+
+// Keep the current configfile as the newest backup, if it can be read. salt is the salt of the
+// master key the configfile is about to be written with, None if it is written in plain.
+// A configfile in another format is not kept, a plain one would leave the vaults readable next
+// to the encrypted configfile.
+fn backup_configfile(path: &Path, salt: Option<&str>) -> io::Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) if is_valid_configfile(&contents) => contents,
+        _ => return Ok(()),
+    };
+    let current_salt = parse_encrypted(&contents).map(|encrypted| encrypted.salt);
+    if current_salt.as_deref() != salt {
+        return Ok(());
+    }
+
+    for i in (1..BACKUP_COUNT).rev() {
+        let older = backup_path(path, i);
+        if older.exists() {
+            fs::rename(&older, backup_path(path, i + 1))?;
+        }
+    }
+
+    write_atomic(&backup_path(path, 1), contents.as_bytes())
+}
+
+// Encrypt the backups of the configfile with a new master key. old_key is the master key they
+// were encrypted with, None if they are plain. Backups that cannot be read with it are removed.
+fn reencrypt_backups(
+    path: &Path,
+    old_key: Option<&(SecretKey, String)>,
+    key: &SecretKey,
+    salt: &str,
+) {
+    for i in 1..=BACKUP_COUNT {
+        let backup = backup_path(path, i);
+        let Ok(contents) = fs::read_to_string(&backup) else {
+            continue;
+        };
+
+        let json = match (parse_encrypted(&contents), old_key) {
+            (Some(encrypted), Some((old_key, old_salt))) if encrypted.salt == *old_salt => {
+                hex::decode(&encrypted.ciphertext)
+                    .ok()
+                    .and_then(|ciphertext| decrypt_file(&ciphertext, old_key).ok())
+            }
+            (None, _) => hex::decode(contents.trim()).ok().map(Zeroizing::new),
+            _ => None,
+        };
+        let json = json.and_then(|json| String::from_utf8(json.to_vec()).ok().map(Zeroizing::new));
+
+        let result = match json {
+            Some(json) => write_atomic(&backup, encrypt_configfile(&json, key, salt).as_bytes()),
+            None => fs::remove_file(&backup),
+        };
+        if let Err(e) = result {
+            println!("Could not encrypt a backup of the configfile: {}", e);
+            if let Err(e) = fs::remove_file(&backup) {
+                println!("Could not remove a backup of the configfile: {}", e);
+            }
+        }
+    }
+}
+
+fn parse_encrypted(contents: &str) -> Option<EncryptedConfig> {
+    serde_json::from_str(contents).ok()
+}
+
+// The config json of an encrypted configfile. None if the key does not open it or it is
+// damaged.
+fn decrypt_configfile(encrypted: &EncryptedConfig, key: &[u8]) -> Option<Zeroizing<String>> {
+    let ciphertext = hex::decode(&encrypted.ciphertext).ok()?;
+    let json = decrypt_file(&ciphertext, key).ok()?;
+    let json = Zeroizing::new(String::from_utf8(json.to_vec()).ok()?);
+    Config::parse(&json).ok()?;
+    Some(json)
+}
+
+fn encrypt_configfile(json_str: &str, key: &[u8], salt: &str) -> String {
+    let encrypted = EncryptedConfig {
+        salt: salt.to_string(),
+        ciphertext: hex::encode(encrypt_file(json_str.as_bytes(), key)),
+    };
+
+    serde_json::to_string(&encrypted).expect("Could not convert the encrypted config to json.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VAULT_FIELDS: &str = r#""path":"/home/user/vault","name":"vault","hash":"hash",
+        "salt":"salt","is_locked":true,"wipe_after":3,"failed_attempts":1,"is_wiped":false"#;
+
+    #[test]
+    fn parse_schema_0() {
+        let json = r#"{"paths":["/home/user/vault"],"names":["vault"],"hashes":["hash"],
+            "salts":["salt"],"is_locked":[true],"wipe_after":[3]}"#;
+        let (config, migrated) = Config::parse(json).unwrap();
+
+        assert!(migrated);
+        assert_eq!(config.schema_version, SCHEMA_VERSION);
+        let vault = &config.vaults[0];
+        assert_eq!(vault.path, "/home/user/vault");
+        assert_eq!(vault.wipe_after, 3);
+        assert_eq!(vault.failed_attempts, 0);
+        assert_eq!(vault.unlocked_with, None);
+        assert!(!vault.id.is_empty());
+        assert_eq!(vault.seed, calculate_seed(&vault.path));
+    }
+
+    #[test]
+    fn parse_schema_1() {
+        let json = format!(r#"{{"schema_version":1,"vaults":[{{{}}}]}}"#, VAULT_FIELDS);
+        let (config, migrated) = Config::parse(&json).unwrap();
+
+        assert!(migrated);
+        assert_eq!(config.schema_version, SCHEMA_VERSION);
+        let vault = &config.vaults[0];
+        assert!(!vault.id.is_empty());
+        assert_eq!(vault.failed_attempts, 1);
+        assert_eq!(vault.seed, calculate_seed(&vault.path));
+    }
+
+    #[test]
+    fn parse_schema_2() {
+        let json = format!(
+            r#"{{"schema_version":2,"vaults":[{{"id":"kept",{}}}]}}"#,
+            VAULT_FIELDS
+        );
+        let (config, migrated) = Config::parse(&json).unwrap();
+
+        assert!(migrated);
+        let vault = &config.vaults[0];
+        assert_eq!(vault.id, "kept");
+        assert_eq!(vault.seed, calculate_seed(&vault.path));
+    }
+
+    #[test]
+    fn parse_current_schema() {
+        let json = serde_json::to_string(&Config::default()).unwrap();
+        let (config, migrated) = Config::parse(&json).unwrap();
+
+        assert!(!migrated);
+        assert!(config.vaults.is_empty());
+
+        let newer = format!(r#"{{"schema_version":{},"vaults":[]}}"#, SCHEMA_VERSION + 1);
+        assert!(Config::parse(&newer).is_err());
     }
 }
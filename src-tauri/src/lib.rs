@@ -1,22 +1,51 @@
-use hex;
-use std::fs::{create_dir_all, File};
-use std::io::Write;
+use std::fs::create_dir_all;
 use tauri::Manager;
 
 mod commands;
 mod config;
+mod parity;
+mod payload;
+mod scrub;
+mod secret;
 mod utils;
+mod vaultfile;
 mod viewmodel;
+mod volume;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
             commands::create_secure_vault,
+            commands::import_vault,
+            commands::scan_for_vaults,
+            commands::relink_vault,
+            commands::reconcile_vault,
             commands::lock_vault,
             commands::unlock_vault,
+            commands::list_vault_contents,
+            commands::verify_vault,
+            commands::extract_from_vault,
+            commands::add_to_vault,
+            commands::remove_from_vault,
+            commands::replace_in_vault,
             commands::get_vaults,
             commands::remove_vault,
+            commands::get_master_password_status,
+            commands::unlock_master_password,
+            commands::set_master_password,
+            commands::get_startup_notices,
+            commands::set_wipe_after,
+            commands::set_compression_level,
+            commands::set_parity_redundancy,
+            commands::repair_vault,
+            commands::set_volume_size,
+            commands::get_scrub_interval,
+            commands::set_scrub_interval,
+            commands::scrub_now,
+            commands::set_decoy,
+            commands::remove_decoy,
+            commands::create_hidden_vault,
         ])
         .setup(|app| {
             if cfg!(debug_assertions) {
@@ -28,14 +57,20 @@ pub fn run() {
             }
 
             create_configfile(app);
+            check_vaults(app);
+            scrub::start(app.handle().clone());
             Ok(())
         })
         .plugin(tauri_plugin_dialog::init())
+        .manage(config::MasterKey::default())
+        .manage(config::StartupNotices::default())
+        .manage(scrub::ScrubKeys::default())
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
 
 // Create the config.json file in app data directory if it doesnt exist.
+// A corrupted configfile is restored from its backups first.
 fn create_configfile(app: &mut tauri::App) {
     let path = app.path();
     if let Ok(mut dir) = path.app_data_dir() {
@@ -46,20 +81,34 @@ fn create_configfile(app: &mut tauri::App) {
             }
         }
 
+        if let Some(notice) = config::recover_configfile(app.handle()) {
+            println!("{}", notice);
+            let notices = app.state::<config::StartupNotices>();
+            notices.0.lock().unwrap().push(notice);
+        }
+
         dir.push("config");
-        if !dir.exists() {
-            match File::create(&dir) {
-                Ok(mut file) => {
-                    let config = config::Config::default();
-                    let json_str = serde_json::to_string(&config)
-                        .expect("Could not conver empty config object to json string.");
-                    let encoded = hex::encode(json_str);
+        if dir.exists() {
+            config::migrate(app.handle());
+        } else if let Err(e) = config::Config::default().to_json(app.handle().clone()) {
+            println!("Error creating the config file: {}", e);
+        }
+    }
+}
 
-                    file.write_all(encoded.as_bytes())
-                        .expect("Could not write empty config strign into file");
-                }
-                Err(e) => println!("Error creating the config file: {}", e),
-            }
+// Tell the user about vaults whose directories do not match the configfile.
+// An encrypted configfile is checked by the frontend once the master password is entered.
+fn check_vaults(app: &mut tauri::App) {
+    let Ok(configfile) = config::Config::from_json(app.handle().clone()) else {
+        return;
+    };
+
+    let notices = app.state::<config::StartupNotices>();
+    for vault in &configfile.vaults {
+        if let Some(problem) = viewmodel::VaultStatus::of(vault).problem() {
+            let notice = format!("{}: {}", vault.name, problem);
+            println!("{}", notice);
+            notices.0.lock().unwrap().push(notice);
         }
     }
 }
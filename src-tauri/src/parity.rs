@@ -0,0 +1,298 @@
+// Reed-Solomon parity over the bytes of a vaultfile, kept next to it in its own file.
+// The vaultfile is cut into DATA_SHARDS pieces and every piece is hashed, so a damaged piece is
+// found without any key and rebuilt from the others and the parity pieces. The parity is over
+// the ciphertext, it says nothing about the files in the vault.
+//
+// Parity file: MAGIC | VERSION | header length (u32 le) | header json | parity pieces
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::write_atomic;
+use crate::volume;
+
+// Being a dotfile, it is never locked into the vault itself.
+pub const PARITY_FILE: &str = ".vaultparity";
+
+const MAGIC: &[u8; 4] = b"SMPF";
+const VERSION: u8 = 1;
+
+// The redundancy percentage is the number of parity pieces.
+const DATA_SHARDS: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+struct ParityHeader {
+    // Length of the vaultfile. The last piece is padded with zeros up to shard_len.
+    len: u64,
+    shard_len: u64,
+    parity_shards: usize,
+    // BLAKE3 hashes of the data pieces, then of the parity pieces.
+    hashes: Vec<String>,
+}
+
+struct Parity {
+    header: ParityHeader,
+    shards: Vec<Vec<u8>>,
+}
+
+// Write the parity of the vaultfile bytes of the vault in path. redundancy is the percentage
+// of the vaultfile that can be damaged and still be repaired, 0 removes the parity.
+pub fn write_parity(path: &Path, bytes: &[u8], redundancy: u32) -> Result<(), String> {
+    if redundancy == 0 {
+        return remove_parity(path);
+    }
+
+    let parity_shards = redundancy as usize;
+    let shard_len = bytes.len().div_ceil(DATA_SHARDS).max(1);
+    let mut shards = data_shards(bytes, shard_len, bytes.len());
+    shards.extend((0..parity_shards).map(|_| vec![0u8; shard_len]));
+    if let Err(e) = reed_solomon(parity_shards)?.encode(&mut shards) {
+        return Err(format!("Could not compute the parity: {:?}", e));
+    }
+
+    let header = ParityHeader {
+        len: bytes.len() as u64,
+        shard_len: shard_len as u64,
+        parity_shards,
+        hashes: shards.iter().map(|shard| shard_hash(shard)).collect(),
+    };
+    let header_bytes =
+        serde_json::to_vec(&header).expect("Could not convert parity header to json.");
+
+    let mut contents = MAGIC.to_vec();
+    contents.push(VERSION);
+    contents.extend_from_slice(&(header_bytes.len() as u32).to_le_bytes());
+    contents.extend_from_slice(&header_bytes);
+    for shard in &shards[DATA_SHARDS..] {
+        contents.extend_from_slice(shard);
+    }
+
+    if let Err(e) = write_atomic(&path.join(PARITY_FILE), &contents) {
+        return Err(format!("Error writing the parity file: {}", e));
+    }
+
+    Ok(())
+}
+
+// Remove the parity of the vault in path, if it has any.
+pub fn remove_parity(path: &Path) -> Result<(), String> {
+    match fs::remove_file(path.join(PARITY_FILE)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(format!("Could not remove the parity file: {}", e))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Count the damaged pieces of the vaultfile and of its parity. 0 if the vault has no parity.
+pub fn damaged_shards(path: &Path) -> Result<usize, String> {
+    let Some(parity) = read_parity(path)? else {
+        return Ok(0);
+    };
+    let bytes = read_vaultfile(path, parity.header.len)?;
+
+    Ok(check_shards(&parity, &bytes)
+        .iter()
+        .filter(|shard| shard.is_none())
+        .count())
+}
+
+// Rebuild the damaged pieces of the vaultfile from its parity and write it back, split into
+// volumes of volume_size. Missing volumes are rebuilt like damaged pieces, and a vaultfile that
+// is gone altogether comes back if the redundancy is 100 percent. The parity is renewed as
+// well, in case its own pieces were damaged.
+// Returns how many pieces were rebuilt.
+pub fn repair(path: &Path, volume_size: u64) -> Result<usize, String> {
+    let Some(parity) = read_parity(path)? else {
+        return Err("The vault has no parity to repair it with!".to_string());
+    };
+    let bytes = read_vaultfile(path, parity.header.len)?;
+
+    let mut shards = check_shards(&parity, &bytes);
+    let damaged = shards.iter().filter(|shard| shard.is_none()).count();
+    if damaged == 0 {
+        return Ok(0);
+    }
+    if damaged > parity.header.parity_shards {
+        return Err(format!(
+            "{} pieces of the vaultfile and its parity are damaged, only {} can be rebuilt!",
+            damaged, parity.header.parity_shards
+        ));
+    }
+
+    if let Err(e) = reed_solomon(parity.header.parity_shards)?.reconstruct(&mut shards) {
+        return Err(format!("Could not rebuild the vaultfile: {:?}", e));
+    }
+
+    let mut repaired: Vec<u8> = shards
+        .into_iter()
+        .take(DATA_SHARDS)
+        .flat_map(|shard| shard.unwrap_or_default())
+        .collect();
+    repaired.truncate(parity.header.len as usize);
+
+    volume::write_vaultfile(path, &repaired, volume_size)?;
+    write_parity(path, &repaired, parity.header.parity_shards as u32)?;
+
+    Ok(damaged)
+}
+
+fn reed_solomon(parity_shards: usize) -> Result<ReedSolomon, String> {
+    ReedSolomon::new(DATA_SHARDS, parity_shards)
+        .map_err(|e| format!("Invalid parity redundancy {}: {:?}", parity_shards, e))
+}
+
+// Cut the first len bytes into DATA_SHARDS pieces of shard_len. Bytes past len are left out,
+// and the pieces are padded with zeros where bytes are missing.
+fn data_shards(bytes: &[u8], shard_len: usize, len: usize) -> Vec<Vec<u8>> {
+    let bytes = &bytes[..len.min(bytes.len())];
+    (0..DATA_SHARDS)
+        .map(|i| {
+            let start = (i * shard_len).min(bytes.len());
+            let end = ((i + 1) * shard_len).min(bytes.len());
+            let mut shard = bytes[start..end].to_vec();
+            shard.resize(shard_len, 0);
+            shard
+        })
+        .collect()
+}
+
+// The pieces of the vaultfile and the parity, None for those that do not match their hash.
+fn check_shards(parity: &Parity, bytes: &[u8]) -> Vec<Option<Vec<u8>>> {
+    let header = &parity.header;
+    let mut shards = data_shards(bytes, header.shard_len as usize, header.len as usize);
+    // Bytes added to the end of the vaultfile move the end of its padding, where a hidden vault
+    // may be. The last piece counts as damaged, so repairing cuts the vaultfile back.
+    if bytes.len() as u64 > header.len {
+        if let Some(last) = shards.last_mut() {
+            last.clear();
+        }
+    }
+    shards.extend(parity.shards.iter().cloned());
+
+    shards
+        .into_iter()
+        .zip(&header.hashes)
+        .map(|(shard, hash)| (shard_hash(&shard) == *hash).then_some(shard))
+        .collect()
+}
+
+fn shard_hash(shard: &[u8]) -> String {
+    blake3::hash(shard).to_hex().to_string()
+}
+
+// The first len bytes of the vaultfile with its missing volumes left as zeros, empty if it is
+// gone.
+fn read_vaultfile(path: &Path, len: u64) -> Result<Vec<u8>, String> {
+    Ok(volume::read_volumes(path, len)?.unwrap_or_default())
+}
+
+// Read the parity file of the vault in path. None if it has none.
+fn read_parity(path: &Path) -> Result<Option<Parity>, String> {
+    let bytes = match fs::read(path.join(PARITY_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not read the parity file: {}", e)),
+    };
+
+    let damaged = || "The parity file is damaged!".to_string();
+    if !bytes.starts_with(MAGIC) {
+        return Err(damaged());
+    }
+    match bytes.get(4) {
+        Some(&VERSION) => {}
+        Some(version) => return Err(format!("Unsupported parity file version: {}", version)),
+        None => return Err(damaged()),
+    }
+
+    let len_bytes = bytes.get(5..9).ok_or_else(damaged)?;
+    let header_len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+    let header_bytes = bytes.get(9..9 + header_len).ok_or_else(damaged)?;
+    let header: ParityHeader = serde_json::from_slice(header_bytes).map_err(|_| damaged())?;
+    if header.hashes.len() != DATA_SHARDS + header.parity_shards {
+        return Err(damaged());
+    }
+
+    // Parity pieces cut short are caught by their hashes.
+    let parity_bytes = &bytes[9 + header_len..];
+    let shard_len = header.shard_len as usize;
+    let shards = (0..header.parity_shards)
+        .map(|i| {
+            let start = (i * shard_len).min(parity_bytes.len());
+            let end = ((i + 1) * shard_len).min(parity_bytes.len());
+            parity_bytes[start..end].to_vec()
+        })
+        .collect();
+
+    Ok(Some(Parity { header, shards }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+    use crate::vaultfile::test_vaultfile;
+
+    fn test_bytes() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn repair_rebuilds_damaged_pieces() {
+        let dir = test_dir();
+        let bytes = test_bytes();
+        fs::write(dir.join("vaultfile"), &bytes).unwrap();
+        write_parity(&dir, &bytes, 10).unwrap();
+        assert_eq!(damaged_shards(&dir).unwrap(), 0);
+
+        let mut damaged = bytes.clone();
+        for i in [0, 50_000, 99_999] {
+            damaged[i] ^= 1;
+        }
+        fs::write(dir.join("vaultfile"), &damaged).unwrap();
+
+        assert_eq!(damaged_shards(&dir).unwrap(), 3);
+        assert_eq!(repair(&dir, 0).unwrap(), 3);
+        assert_eq!(fs::read(dir.join("vaultfile")).unwrap(), bytes);
+        assert_eq!(damaged_shards(&dir).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repair_refuses_too_much_damage() {
+        let dir = test_dir();
+        let bytes = test_bytes();
+        fs::write(dir.join("vaultfile"), &bytes).unwrap();
+        write_parity(&dir, &bytes, 2).unwrap();
+
+        let mut damaged = bytes.clone();
+        for i in [0, 50_000, 99_999] {
+            damaged[i] ^= 1;
+        }
+        fs::write(dir.join("vaultfile"), &damaged).unwrap();
+
+        assert!(repair(&dir, 0).is_err());
+        assert_eq!(fs::read(dir.join("vaultfile")).unwrap(), damaged);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repair_rebuilds_missing_volume() {
+        let dir = test_dir();
+        let bytes = test_vaultfile().to_volume_bytes(4096, None);
+        volume::write_vaultfile(&dir, &bytes, 4096).unwrap();
+        write_parity(&dir, &bytes, 50).unwrap();
+        fs::remove_file(volume::volume_path(&dir, 2)).unwrap();
+
+        assert!(repair(&dir, 4096).unwrap() > 0);
+        assert_eq!(volume::read_vaultfile(&dir).unwrap(), bytes);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
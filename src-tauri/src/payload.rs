@@ -0,0 +1,544 @@
+// Layout of the payload of a slot or a hidden vault:
+//
+// chunk | chunk | ... | manifest
+//
+// Files are cut into chunks at content-defined boundaries and every chunk is encrypted on its
+// own, so single files can be read, added or dropped without decrypting the rest. The manifest
+// lists the files with the chunks that hold them. It is encrypted as well and its length is
+// kept in the slot header.
+//
+// The manifest keeps a keyed hash of every chunk and of every file's contents. A chunk that is
+// already in the payload is stored once, however many files contain it. Locking looks up the
+// chunks of the files in the directory in the payload the vault was unlocked from, and chunks
+// that did not change are copied instead of being encrypted again. The hash key is derived from
+// the vault key, so equal chunks in different vaults do not have equal hashes.
+//
+// Chunks can be compressed with zstd before they are encrypted. Each chunk records whether it
+// was, so a vault can mix both and files are decompressed when they are read.
+//
+// Payloads written before this layout are a single encrypted blob of every file, shuffled with
+// the seed of the vault. They have no manifest and are moved into chunks when opened.
+
+use fastcdc::v2020::FastCDC;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::secret::Plaintext;
+use crate::utils::{decrypt_file, encrypt_file, unshuffle_bytes};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    pub files: Vec<FileEntry>,
+    // Whether the vault has a decoy. Its metadata then describes the decoy, so it is not
+    // renewed from the vault.
+    #[serde(default)]
+    pub has_decoy: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+    // Seconds since the unix epoch. Not known for files locked before the manifest existed.
+    pub modified: Option<u64>,
+    // Hex encoded keyed BLAKE3 hash of the contents. Not known for files added before it was
+    // kept.
+    #[serde(default)]
+    pub hash: Option<String>,
+    pub chunks: Vec<ChunkRef>,
+}
+
+// How a restored file compares with the hash it was locked with.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Checksum {
+    Verified,
+    Mismatch,
+    // The file was locked before hashes were kept.
+    Unknown,
+}
+
+// Where an encrypted chunk is in the payload.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChunkRef {
+    pub offset: u64,
+    pub len: u64,
+    // Hex encoded keyed BLAKE3 hash of the plaintext. Not known for chunks added before it was
+    // kept, those are never shared.
+    #[serde(default)]
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub compressed: bool,
+}
+
+// Bounds for the size of a chunk, in bytes.
+const MIN_CHUNK: u32 = 16 * 1024;
+const AVG_CHUNK: u32 = 64 * 1024;
+const MAX_CHUNK: u32 = 256 * 1024;
+
+#[derive(Default)]
+pub struct Payload {
+    pub manifest: Manifest,
+    // zstd level for the chunks that are added. 0 stores them as they are.
+    pub compression_level: i32,
+    chunks: Vec<u8>,
+    // The chunks of the payload by their hash.
+    index: HashMap<String, ChunkRef>,
+}
+
+impl FileEntry {
+    pub fn check(&self, contents: &[u8], key: &[u8]) -> Checksum {
+        match &self.hash {
+            Some(hash) if *hash == content_hash(contents, key) => Checksum::Verified,
+            Some(_) => Checksum::Mismatch,
+            None => Checksum::Unknown,
+        }
+    }
+}
+
+impl Payload {
+    // Open an encrypted payload. manifest_len is 0 for payloads in the old layout.
+    pub fn open(bytes: &[u8], manifest_len: usize, key: &[u8], seed: u64) -> Result<Self, String> {
+        if manifest_len == 0 {
+            return Payload::from_legacy(bytes, key, seed);
+        }
+
+        let manifest_start = bytes
+            .len()
+            .checked_sub(manifest_len)
+            .ok_or("The payload is truncated!")?;
+        let manifest_json = decrypt_file(&bytes[manifest_start..], key)?;
+        let manifest = match serde_json::from_slice(&manifest_json) {
+            Ok(manifest) => manifest,
+            Err(e) => return Err(format!("Could not parse the vault manifest: {}", e)),
+        };
+
+        let mut payload = Payload {
+            manifest,
+            compression_level: 0,
+            chunks: bytes[..manifest_start].to_vec(),
+            index: HashMap::new(),
+        };
+        payload.index_chunks();
+        Ok(payload)
+    }
+
+    // Returns the encrypted payload and the length of the manifest at its end.
+    pub fn seal(&self, key: &[u8]) -> (Vec<u8>, usize) {
+        let manifest_json = Zeroizing::new(
+            serde_json::to_vec(&self.manifest).expect("Could not convert the manifest to json."),
+        );
+        let manifest = encrypt_file(&manifest_json, key);
+
+        let mut bytes = Vec::with_capacity(self.chunks.len() + manifest.len());
+        bytes.extend_from_slice(&self.chunks);
+        bytes.extend_from_slice(&manifest);
+        (bytes, manifest.len())
+    }
+
+    // Encrypt a file into the payload. A file of the same name is replaced.
+    // Chunks that are already in the payload are shared, and chunks that are in the previous
+    // payload of the vault are copied from it instead of being encrypted again.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        contents: &[u8],
+        modified: Option<u64>,
+        key: &[u8],
+        previous: Option<&Payload>,
+    ) -> Result<(), String> {
+        let hash_key = hash_key(key);
+        let compress = self.compression_level != 0 && !is_compressed_format(contents);
+        let mut chunks = vec![];
+        for cut in FastCDC::new(contents, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+            let data = &contents[cut.offset..cut.offset + cut.length];
+            let hash = blake3::keyed_hash(&hash_key, data).to_hex().to_string();
+            if let Some(chunk) = self.index.get(&hash) {
+                chunks.push(chunk.clone());
+                continue;
+            }
+
+            let offset = self.chunks.len() as u64;
+            let compressed =
+                match previous.and_then(|previous| Some((previous, previous.index.get(&hash)?))) {
+                    Some((previous, chunk)) => {
+                        let bytes = previous.chunk_bytes(chunk, name)?;
+                        self.chunks.extend_from_slice(bytes);
+                        chunk.compressed
+                    }
+                    None => {
+                        // Keep the chunk as it is if compressing does not make it smaller.
+                        let compressed = if compress {
+                            Some(compress_chunk(data, self.compression_level)?)
+                                .filter(|compressed| compressed.len() < data.len())
+                        } else {
+                            None
+                        };
+                        let plaintext = compressed.as_deref().map_or(data, |c| c.as_slice());
+                        self.chunks.extend_from_slice(&encrypt_file(plaintext, key));
+                        compressed.is_some()
+                    }
+                };
+
+            let chunk = ChunkRef {
+                offset,
+                len: self.chunks.len() as u64 - offset,
+                hash: Some(hash.clone()),
+                compressed,
+            };
+            self.index.insert(hash, chunk.clone());
+            chunks.push(chunk);
+        }
+
+        self.manifest.files.retain(|file| file.name != name);
+        self.manifest.files.push(FileEntry {
+            name: name.to_string(),
+            size: contents.len() as u64,
+            modified,
+            hash: Some(content_hash(contents, key)),
+            chunks,
+        });
+        Ok(())
+    }
+
+    // Returns false if there is no file of that name. Its chunks stay until the payload is
+    // compacted.
+    pub fn remove_file(&mut self, name: &str) -> bool {
+        let len = self.manifest.files.len();
+        self.manifest.files.retain(|file| file.name != name);
+        self.manifest.files.len() != len
+    }
+
+    // Drop the chunks no file refers to. Chunks are copied as they are, nothing is decrypted.
+    pub fn compact(&mut self) -> Result<(), String> {
+        let mut chunks = vec![];
+        // Chunks shared by several files are copied once.
+        let mut moved = HashMap::new();
+        for file in &mut self.manifest.files {
+            for chunk in &mut file.chunks {
+                let offset = match moved.get(&chunk.offset) {
+                    Some(&offset) => offset,
+                    None => {
+                        let start = chunk.offset as usize;
+                        let bytes = self
+                            .chunks
+                            .get(start..start + chunk.len as usize)
+                            .ok_or_else(|| format!("A chunk of {} is missing!", file.name))?;
+                        let offset = chunks.len() as u64;
+                        chunks.extend_from_slice(bytes);
+                        moved.insert(chunk.offset, offset);
+                        offset
+                    }
+                };
+                chunk.offset = offset;
+            }
+        }
+
+        self.chunks = chunks;
+        self.index_chunks();
+        Ok(())
+    }
+
+    pub fn file(&self, name: &str) -> Option<&FileEntry> {
+        self.manifest.files.iter().find(|file| file.name == name)
+    }
+
+    // Decrypt the contents of a file.
+    pub fn read_file(&self, file: &FileEntry, key: &[u8]) -> Result<Plaintext, String> {
+        let mut contents = Plaintext::new(Vec::with_capacity(file.size as usize));
+        for chunk in &file.chunks {
+            let bytes = self.chunk_bytes(chunk, &file.name)?;
+            let plaintext = decrypt_file(bytes, key)?;
+            if chunk.compressed {
+                contents.extend_from_slice(&decompress_chunk(&plaintext)?);
+            } else {
+                contents.extend_from_slice(&plaintext);
+            }
+        }
+
+        Ok(contents)
+    }
+
+    // The encrypted bytes of a chunk of the named file.
+    fn chunk_bytes(&self, chunk: &ChunkRef, name: &str) -> Result<&[u8], String> {
+        let start = chunk.offset as usize;
+        self.chunks
+            .get(start..start + chunk.len as usize)
+            .ok_or_else(|| format!("A chunk of {} is missing!", name))
+    }
+
+    fn index_chunks(&mut self) {
+        self.index = HashMap::new();
+        for chunk in self.manifest.files.iter().flat_map(|file| &file.chunks) {
+            if let Some(hash) = &chunk.hash {
+                self.index.insert(hash.clone(), chunk.clone());
+            }
+        }
+    }
+
+    // Move the files of a payload in the old layout into chunks.
+    fn from_legacy(bytes: &[u8], key: &[u8], seed: u64) -> Result<Self, String> {
+        let mut plaintext = decrypt_file(bytes, key)?;
+        unshuffle_bytes(&mut plaintext, seed);
+
+        let mut payload = Payload::default();
+        for (name, contents) in legacy_files(&plaintext)? {
+            payload.add_file(&name, contents, None, key, None)?;
+        }
+
+        Ok(payload)
+    }
+}
+
+// The key for the hashes of chunks and files, derived from the vault key.
+fn hash_key(key: &[u8]) -> [u8; 32] {
+    blake3::derive_key("SecureMe 2024-11 vault content hash", key)
+}
+
+// Keyed hash of the contents of a file, hex encoded.
+fn content_hash(contents: &[u8], key: &[u8]) -> String {
+    blake3::keyed_hash(&hash_key(key), contents)
+        .to_hex()
+        .to_string()
+}
+
+fn compress_chunk(data: &[u8], level: i32) -> Result<Plaintext, String> {
+    match zstd::bulk::compress(data, level) {
+        Ok(compressed) => Ok(Plaintext::new(compressed)),
+        Err(e) => Err(format!("Could not compress a chunk: {}", e)),
+    }
+}
+
+fn decompress_chunk(data: &[u8]) -> Result<Plaintext, String> {
+    match zstd::bulk::decompress(data, MAX_CHUNK as usize) {
+        Ok(decompressed) => Ok(Plaintext::new(decompressed)),
+        Err(e) => Err(format!("Could not decompress a chunk: {}", e)),
+    }
+}
+
+// Returns true if the contents start like a format that is compressed already, so compressing
+// them again would only cost time.
+fn is_compressed_format(contents: &[u8]) -> bool {
+    const MAGICS: [&[u8]; 15] = [
+        b"PK\x03\x04",         // zip, docx, xlsx, jar, apk
+        b"\x1f\x8b",           // gzip
+        b"\x28\xb5\x2f\xfd",   // zstd
+        b"\xfd7zXZ\x00",       // xz
+        b"BZh",                // bzip2
+        b"7z\xbc\xaf\x27\x1c", // 7z
+        b"Rar!",               // rar
+        b"\x89PNG",            // png
+        b"\xff\xd8\xff",       // jpeg
+        b"GIF8",               // gif
+        b"ID3",                // mp3
+        b"OggS",               // ogg
+        b"fLaC",               // flac
+        b"\x1a\x45\xdf\xa3",   // mkv, webm
+        b"%PDF",               // pdf, its streams are usually compressed
+    ];
+
+    // mp4, mov, heic and webp carry their type after a length or size field.
+    MAGICS.iter().any(|magic| contents.starts_with(magic))
+        || contents.get(4..8) == Some(&b"ftyp"[..])
+        || contents.get(8..12) == Some(&b"WEBP"[..])
+}
+
+// Returns true if a name from a manifest is a plain file name, so restoring it cannot write
+// outside the directory it is restored to.
+pub fn is_safe_name(name: &str) -> bool {
+    let path = Path::new(name);
+    !name.is_empty() && path.file_name().and_then(|n| n.to_str()) == Some(name)
+}
+
+// Split the plaintext of an old payload into its files. It looks like:
+//
+// size table length | size | size | ... | name length | name | contents | name length | ...
+//
+// where every size covers a file's name length, name and contents.
+fn legacy_files(plaintext: &[u8]) -> Result<Vec<(String, &[u8])>, String> {
+    let truncated = || "The vault contents are truncated!".to_string();
+
+    let sizes_len = *plaintext.first().ok_or_else(truncated)? as usize;
+    let sizes = plaintext.get(1..=sizes_len).ok_or_else(truncated)?;
+    let mut data = &plaintext[sizes_len + 1..];
+
+    let mut files = vec![];
+    for size_bytes in sizes.chunks_exact(8) {
+        let size = u64::from_le_bytes(size_bytes.try_into().unwrap()) as usize;
+        let file_bytes = data.get(..size).ok_or_else(truncated)?;
+        data = &data[size..];
+
+        let name_len = *file_bytes.first().ok_or_else(truncated)? as usize;
+        let name_bytes = file_bytes.get(1..=name_len).ok_or_else(truncated)?;
+        let name = match std::str::from_utf8(name_bytes) {
+            Ok(name) => name.to_string(),
+            Err(e) => return Err(format!("A file name in the vault is not utf8: {}", e)),
+        };
+
+        files.push((name, &file_bytes[name_len + 1..]));
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+
+    const KEY: [u8; 32] = [1; 32];
+
+    // Contents that do not compress, long enough to be cut into several chunks. They are seeded
+    // with their length, so the chunks are cut at the same places on every run.
+    fn random_contents(len: usize) -> Vec<u8> {
+        let mut contents = vec![0u8; len];
+        StdRng::seed_from_u64(len as u64).fill_bytes(&mut contents);
+        contents
+    }
+
+    // Seal the payload and open it again, as locking and unlocking the vault does.
+    fn reopen(payload: &Payload) -> Payload {
+        let (bytes, manifest_len) = payload.seal(&KEY);
+        Payload::open(&bytes, manifest_len, &KEY, 0).unwrap()
+    }
+
+    fn read(payload: &Payload, name: &str) -> Vec<u8> {
+        let file = payload.file(name).unwrap();
+        payload.read_file(file, &KEY).unwrap().to_vec()
+    }
+
+    #[test]
+    fn shared_chunks_are_stored_once() {
+        let contents = random_contents(600_000);
+        let mut payload = Payload::default();
+        payload.add_file("a", &contents, None, &KEY, None).unwrap();
+        let len = payload.chunks.len();
+        assert!(payload.file("a").unwrap().chunks.len() > 1);
+
+        payload.add_file("b", &contents, None, &KEY, None).unwrap();
+        assert_eq!(payload.chunks.len(), len);
+
+        // A file that only shares its end with another adds the chunks before it.
+        let mut changed = random_contents(100_000);
+        changed.extend_from_slice(&contents[300_000..]);
+        payload.add_file("c", &changed, None, &KEY, None).unwrap();
+        assert!(payload.chunks.len() < len + changed.len());
+
+        let payload = reopen(&payload);
+        assert_eq!(read(&payload, "a"), contents);
+        assert_eq!(read(&payload, "b"), contents);
+        assert_eq!(read(&payload, "c"), changed);
+    }
+
+    #[test]
+    fn compact_drops_unused_chunks() {
+        let kept = random_contents(200_000);
+        let mut payload = Payload::default();
+        payload.add_file("kept", &kept, None, &KEY, None).unwrap();
+        payload.add_file("copy", &kept, None, &KEY, None).unwrap();
+        let len = payload.chunks.len();
+        payload
+            .add_file("removed", &random_contents(300_000), None, &KEY, None)
+            .unwrap();
+
+        assert!(payload.remove_file("removed"));
+        assert!(!payload.remove_file("removed"));
+        payload.compact().unwrap();
+
+        // The chunks the two copies share are kept once.
+        assert_eq!(payload.chunks.len(), len);
+        let payload = reopen(&payload);
+        assert_eq!(read(&payload, "kept"), kept);
+        assert_eq!(read(&payload, "copy"), kept);
+        assert!(payload.file("removed").is_none());
+    }
+
+    #[test]
+    fn chunks_are_compressed_when_it_helps() {
+        let text = b"the same line of text over and over\n".repeat(10_000);
+        let random = random_contents(100_000);
+        let mut zip = b"PK\x03\x04".to_vec();
+        zip.extend_from_slice(&text);
+
+        let mut payload = Payload {
+            compression_level: 3,
+            ..Payload::default()
+        };
+        for (name, contents) in [("text", &text), ("random", &random), ("zip", &zip)] {
+            payload.add_file(name, contents, None, &KEY, None).unwrap();
+        }
+        let compressed = |name| {
+            payload
+                .file(name)
+                .unwrap()
+                .chunks
+                .iter()
+                .all(|c| c.compressed)
+        };
+        assert!(compressed("text"));
+        assert!(!payload
+            .file("random")
+            .unwrap()
+            .chunks
+            .iter()
+            .any(|c| c.compressed));
+        assert!(!payload
+            .file("zip")
+            .unwrap()
+            .chunks
+            .iter()
+            .any(|c| c.compressed));
+
+        let payload = reopen(&payload);
+        assert_eq!(read(&payload, "text"), text);
+        assert_eq!(read(&payload, "random"), random);
+        assert_eq!(read(&payload, "zip"), zip);
+    }
+
+    #[test]
+    fn compression_level_0_stores_chunks_as_they_are() {
+        let text = b"the same line of text over and over\n".repeat(10_000);
+        let mut payload = Payload::default();
+        payload.add_file("text", &text, None, &KEY, None).unwrap();
+
+        assert!(!payload
+            .file("text")
+            .unwrap()
+            .chunks
+            .iter()
+            .any(|c| c.compressed));
+        assert!(payload.chunks.len() > text.len());
+        assert_eq!(read(&reopen(&payload), "text"), text);
+    }
+
+    #[test]
+    fn legacy_files_splits_old_payload() {
+        let files: [(&str, &[u8]); 3] = [("a.txt", b"first"), ("empty", b""), ("b.bin", &[0, 1])];
+
+        // What create_vaultfile_bytes wrote before it was shuffled.
+        let mut sizes = vec![];
+        let mut data = vec![];
+        for (name, contents) in files {
+            sizes.extend_from_slice(&((1 + name.len() + contents.len()) as u64).to_le_bytes());
+            data.push(name.len() as u8);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(contents);
+        }
+        let mut plaintext = vec![sizes.len() as u8];
+        plaintext.extend_from_slice(&sizes);
+        plaintext.extend_from_slice(&data);
+
+        let parsed = legacy_files(&plaintext).unwrap();
+        let expected: Vec<_> = files
+            .iter()
+            .map(|(name, contents)| (name.to_string(), *contents))
+            .collect();
+        assert_eq!(parsed, expected);
+
+        assert!(legacy_files(&plaintext[..plaintext.len() - 1]).is_err());
+        assert!(legacy_files(&[]).is_err());
+    }
+}
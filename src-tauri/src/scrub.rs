@@ -0,0 +1,132 @@
+// Checks locked vaults in the background, so damage to a vaultfile is found while there is
+// still a copy of the files to restore it from.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tauri::{Emitter, Manager};
+
+use crate::config::{Config, VaultEntry};
+use crate::parity;
+use crate::secret::SecretKey;
+use crate::utils::OpenedVault;
+use crate::vaultfile::Vaultfile;
+use crate::viewmodel::{
+    check_header, check_metadata, DamagedVaultViewModel, VaultStatus, VerifyReportViewModel,
+    HEADER_PROBLEM,
+};
+use crate::volume;
+
+// Emitted once for every damaged vault a scrub finds.
+pub const DAMAGED_EVENT: &str = "vault-damaged";
+
+// How often the background task looks at the configured interval.
+const TICK: Duration = Duration::from_secs(60);
+
+// Keys of the vaults the user asked to keep verifying, by vault id. They are only kept in
+// memory, so the files of those vaults are checked until the app is closed.
+// A scrub clones the key it needs out of the map, so the commands that change it are not kept
+// waiting while a whole vault is checked.
+#[derive(Default)]
+pub struct ScrubKeys(pub Mutex<HashMap<String, Arc<SecretKey>>>);
+
+// Start scrubbing in the background. The interval is read from the configfile every tick, so
+// changing it needs no restart. Nothing is checked while the configfile cannot be read, such as
+// before the master password is entered.
+pub fn start(handle: tauri::AppHandle) {
+    thread::spawn(move || {
+        let mut last_scrub = Instant::now();
+        loop {
+            thread::sleep(TICK);
+
+            let Ok(configfile) = Config::from_json(handle.clone()) else {
+                continue;
+            };
+            let interval = Duration::from_secs(configfile.scrub_interval * 60 * 60);
+            if configfile.scrub_interval == 0 || last_scrub.elapsed() < interval {
+                continue;
+            }
+
+            last_scrub = Instant::now();
+            scrub_vaults(&configfile, &handle);
+        }
+    });
+}
+
+// Check every locked vault and emit an event for each damaged one.
+// Returns what was found.
+pub fn scrub_vaults(configfile: &Config, handle: &tauri::AppHandle) -> Vec<DamagedVaultViewModel> {
+    let keys = handle.state::<ScrubKeys>();
+    let mut damaged = vec![];
+
+    for vault in &configfile.vaults {
+        if !vault.is_locked || vault.is_wiped {
+            continue;
+        }
+
+        let key = keys.0.lock().unwrap().get(&vault.id).cloned();
+        if let Some(problem) = scrub_vault(vault, key.as_deref()) {
+            println!(
+                "Scrubbing found a damaged vault {}: {}",
+                vault.name, problem
+            );
+            let report = DamagedVaultViewModel::new(vault, problem);
+            if let Err(e) = handle.emit(DAMAGED_EVENT, &report) {
+                println!("Error reporting a damaged vault: {}", e);
+            }
+            damaged.push(report);
+        }
+    }
+
+    damaged
+}
+
+// Check the vaultfile of a locked vault. Without a key only the header can be authenticated,
+// with one every chunk is decrypted and every file compared with its hash.
+// Returns what is wrong with the vault, None if nothing is.
+fn scrub_vault(vault: &VaultEntry, key: Option<&SecretKey>) -> Option<String> {
+    match VaultStatus::of(vault) {
+        VaultStatus::Present => {}
+        // A drive that is not mounted is not damage.
+        VaultStatus::Missing => return None,
+        status => return status.problem().map(|problem| problem.to_string()),
+    }
+
+    let path = Path::new(&vault.path);
+    match parity::damaged_shards(path) {
+        Ok(0) => {}
+        Ok(damaged) => {
+            return Some(format!(
+                "{} pieces of the vaultfile or its parity are damaged. Repair the vault to \
+                 rebuild them from its parity.",
+                damaged
+            ))
+        }
+        Err(e) => return Some(e),
+    }
+
+    let bytes = match volume::read_vaultfile(path) {
+        Ok(bytes) => bytes,
+        Err(e) => return Some(e),
+    };
+    if let Err(e) = Vaultfile::from_bytes(&bytes) {
+        return Some(e);
+    }
+    if check_metadata(vault) == Some(false) {
+        return Some("The metadata in the vaultfile does not authenticate.".to_string());
+    }
+    if check_header(vault, path) == Some(false) {
+        return Some(HEADER_PROBLEM.to_string());
+    }
+
+    let key = key?;
+    let opened = match OpenedVault::open(path, key, vault.seed) {
+        Ok(Some(opened)) => opened,
+        Ok(None) => return Some("The vaultfile no longer opens with its key.".to_string()),
+        Err(e) => return Some(e),
+    };
+    VerifyReportViewModel::new(vault, &opened.payload, key).problem()
+}
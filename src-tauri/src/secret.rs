@@ -0,0 +1,109 @@
+// Types for passwords, keys and plaintext that are zeroed when they are dropped.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use zeroize::{Zeroize, Zeroizing};
+
+// A password as it arrives from the frontend.
+pub type Password = Zeroizing<String>;
+
+// Decrypted vault contents.
+pub type Plaintext = Zeroizing<Vec<u8>>;
+
+// The pages locked into RAM for keys, with how many live keys are on each. Locks do not nest,
+// so a page is only unlocked once the last key on it is dropped.
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+// A 256 bit key. It lives on the heap so it is never copied when moved, and its pages are locked
+// into RAM where the OS allows it so it is never swapped out.
+pub struct SecretKey {
+    bytes: Box<[u8; 32]>,
+
+    // The pages this key counts towards in LOCKED_PAGES. Released after the key is zeroed.
+    pages: Vec<usize>,
+}
+
+impl SecretKey {
+    pub fn new() -> Self {
+        let bytes = Box::new([0u8; 32]);
+        let pages = lock_pages(bytes.as_slice());
+
+        SecretKey { bytes, pages }
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+}
+
+impl DerefMut for SecretKey {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.bytes.as_mut_slice()
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        unlock_pages(&self.pages);
+    }
+}
+
+// The start of every page bytes is on.
+fn pages_of(bytes: &[u8]) -> Vec<usize> {
+    let page_size = region::page::size();
+    let start = region::page::floor(bytes.as_ptr()) as usize;
+    let end = bytes.as_ptr() as usize + bytes.len();
+
+    (start..end).step_by(page_size).collect()
+}
+
+// Lock the pages bytes is on, unless other keys already did. Returns the pages it counts
+// towards. Locking fails without the privileges for it, such pages are not counted and the key
+// is still zeroed on drop.
+fn lock_pages(bytes: &[u8]) -> Vec<usize> {
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+    let mut pages = vec![];
+
+    for page in pages_of(bytes) {
+        match locked_pages.get_mut(&page) {
+            Some(count) => *count += 1,
+            None => match region::lock(page as *const u8, region::page::size()) {
+                // The page stays locked until unlock_pages releases it.
+                Ok(guard) => {
+                    mem::forget(guard);
+                    locked_pages.insert(page, 1);
+                }
+                Err(_) => continue,
+            },
+        }
+        pages.push(page);
+    }
+
+    pages
+}
+
+// Release pages counted by lock_pages and unlock the ones no other key is on.
+fn unlock_pages(pages: &[usize]) {
+    let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+
+    for page in pages {
+        let Some(count) = locked_pages.get_mut(page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked_pages.remove(page);
+            if let Err(e) = region::unlock(*page as *const u8, region::page::size()) {
+                println!("Could not unlock the memory of a key: {}", e);
+            }
+        }
+    }
+}
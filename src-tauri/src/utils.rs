@@ -1,6 +1,7 @@
 use std::fs::{self, DirEntry};
-use std::io::Write;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use rand::prelude::*;
 use rand::rngs::StdRng;
@@ -15,95 +16,587 @@ use aes_gcm::{
 };
 
 use crate::config::Config;
+use crate::parity::write_parity;
+use crate::payload::{is_safe_name, Checksum, FileEntry, Manifest, Payload};
+use crate::secret::{Plaintext, SecretKey};
+use crate::vaultfile::{
+    self, filler_slot, seal_slot, slot_len, Compartment, VaultInfo, VaultMetadata, Vaultfile,
+};
+use crate::volume;
+
+// How the vaultfile of a vault is kept on disk.
+#[derive(Default, Clone, Copy)]
+pub struct Storage {
+    // Percentage of the vaultfile that its parity can rebuild. 0 means it has none.
+    pub parity_redundancy: u32,
+    // Size of the volumes the vaultfile is split into. 0 keeps it in one file.
+    pub volume_size: u64,
+    // Key the header of the vaultfile is authenticated with. None leaves it unauthenticated.
+    pub header_key: Option<[u8; 32]>,
+    // Whether the header on disk was written with a MAC, so one without it is not trusted.
+    pub header_mac: bool,
+}
+
+// While a vault is unlocked its vaultfile is kept under this name, so the slot that was not
+// opened survives the next lock. Being a dotfile, it is never locked into the vault itself.
+pub const RETAINED_VAULTFILE: &str = ".vaultfile";
+
+// Identifies the key a vault was unlocked with, hex encoded, without revealing the key.
+pub fn key_fingerprint(key: &[u8]) -> String {
+    hex::encode(blake3::derive_key(
+        "SecureMe 2024 unlock key fingerprint",
+        key,
+    ))
+}
 
 // Utility function to lock a vault.
-pub fn lock_vault_util(path: &str, key: &[u8]) -> Result<(), String> {
+// The contents of the directory replace whatever the key opened in the vaultfile that was kept
+// when the vault was unlocked: a slot or the hidden vault. Everything else is kept as is, except
+// that locking a slot renews the padding. A hidden vault survives that only if hidden_key is
+// given. A vault that has no kept vaultfile gets random filler in its other slot.
+// The vaultfile is described with info, so it can be imported again. Its metadata is renewed
+// if metadata_key is given, unless the hidden vault is locked or the vault has a decoy the
+// metadata describes instead.
+// Chunks that are encrypted again are compressed with compression_level, 0 turns it off.
+// The vaultfile is kept on disk as storage says.
+pub fn lock_vault_util(
+    path: &str,
+    key: &[u8],
+    hidden_key: Option<&[u8]>,
+    info: &VaultInfo,
+    metadata_key: Option<&[u8]>,
+    compression_level: i32,
+    storage: Storage,
+) -> Result<(), String> {
     let path = Path::new(path);
 
-    // Count the entries in the directory and place the file count at the start
-    let entries: Vec<DirEntry> = path
-        .read_dir()
-        .expect("Could not read dirs")
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| !is_dotfile(&entry))
-        .collect();
-
-    let seed = calculate_seed(path.to_str().unwrap());
-    let vaultfile_bytes = create_vaultfile_bytes(&entries, seed)?;
-    let ciphertext = encrypt_file(&vaultfile_bytes, key);
-
-    let mut vaultfile = fs::File::create(format!("{}/vaultfile", path.to_str().unwrap())).unwrap();
-    if let Err(e) = vaultfile.write_all(&ciphertext) {
-        return Err(format!(
-            "Error writing ciphertext bytes into vaultfile: {}",
-            e
-        ));
+    // Find where the contents go before touching any files, so a wrong key leaves the
+    // directory as it is.
+    let retained_path = path.join(RETAINED_VAULTFILE);
+    let retained = match fs::read(&retained_path) {
+        Ok(bytes) => Vaultfile::from_bytes(&bytes)?,
+        Err(_) => None,
+    };
+    let compartment = retained.as_ref().and_then(|retained| retained.find(key));
+    if retained.is_some() && compartment.is_none() {
+        return Err("The key does not open the vault being locked!".to_string());
+    }
+    let is_hidden = compartment == Some(Compartment::Hidden);
+
+    let kept_hidden = match (hidden_key, &retained) {
+        // The hidden vault is the one being locked, there is nothing else to keep.
+        (Some(hidden_key), _) if is_hidden && hidden_key != key => {
+            return Err("Lock the hidden vault with its own password alone!".to_string())
+        }
+        (Some(_), _) if is_hidden => None,
+        (Some(hidden_key), Some(retained)) => Some(
+            retained
+                .hidden_region(hidden_key)
+                .ok_or("The hidden password does not open a hidden vault!")?
+                .to_vec(),
+        ),
+        (Some(_), None) => return Err("This vault has no hidden vault to keep!".to_string()),
+        (None, _) => None,
+    };
+
+    // Chunks that did not change since the vault was unlocked are not encrypted again.
+    let seed = info
+        .seed
+        .unwrap_or_else(|| calculate_seed(path.to_str().unwrap()));
+    let previous = match (&retained, compartment) {
+        (Some(retained), Some(compartment)) => {
+            let (payload, manifest_len) = retained.payload(compartment, key)?;
+            Payload::open(payload, manifest_len, key, seed).ok()
+        }
+        _ => None,
+    };
+
+    let entries = read_entries(path)?;
+    let mut payload = build_payload(&entries, key, previous.as_ref(), compression_level)?;
+    payload.manifest.has_decoy = previous.is_some_and(|previous| previous.manifest.has_decoy);
+    let metadata = metadata_key
+        .filter(|_| !is_hidden && !payload.manifest.has_decoy)
+        .map(|metadata_key| vault_metadata(&payload.manifest).seal(metadata_key));
+
+    let (payload, manifest_len) = payload.seal(key);
+
+    // A hidden vault that does not fit fails here, before any file is touched.
+    let mut vaultfile = match (retained, compartment) {
+        (Some(mut retained), Some(Compartment::Slot(index))) => {
+            retained.slots[index] = seal_slot(&payload, manifest_len, key, slot_len(payload.len()));
+            retained.shuffle_slots();
+            retained.fill_padding(kept_hidden.as_deref())?;
+            retained
+        }
+        (Some(mut retained), _) => {
+            retained.seal_hidden(&payload, manifest_len, key)?;
+            retained
+        }
+        (None, _) => new_vaultfile(&payload, manifest_len, key),
+    };
+    vaultfile.info = Some(info.clone());
+    if metadata.is_some() {
+        vaultfile.metadata = metadata;
+    }
+
+    write_vaultfile(path, &vaultfile, storage)?;
+
+    // Remove the plaintext files only once their contents are safely in the vaultfile.
+    for entry in &entries {
+        if let Err(e) = fs::remove_file(entry.path()) {
+            return Err(format!("Error removing plaintext file: {}", e));
+        }
+    }
+
+    if retained_path.exists() {
+        if let Err(e) = fs::remove_file(&retained_path) {
+            return Err(format!("Error removing the retained vaultfile: {}", e));
+        }
     }
 
     Ok(())
 }
 
-// Reconstruct the files of the directory from the decrypted vault bytes.
-pub fn reconstruct_files(
-    mut plaintext_bytes: Vec<u8>,
-    path: &std::path::Path,
+// The part of a locked vaultfile a key opens, with its payload opened so files can be read,
+// added or removed without unlocking the vault.
+pub struct OpenedVault {
+    vaultfile: Vaultfile,
+    compartment: Compartment,
+    pub payload: Payload,
+}
+
+impl OpenedVault {
+    // Open the vaultfile of a locked vault with the given key. The seed is only needed for
+    // payloads in the old layout. Returns None if the key opens neither the vault, its decoy
+    // nor its hidden vault.
+    pub fn open(path: &Path, key: &[u8], seed: u64) -> Result<Option<Self>, String> {
+        let bytes = volume::read_vaultfile(path)?;
+
+        let vaultfile = match Vaultfile::from_bytes(&bytes)? {
+            Some(vaultfile) => vaultfile,
+            None => match Payload::open(&bytes, 0, key, seed) {
+                // The old layout has no manifest. Saving moves it into a slot.
+                Ok(_) => new_vaultfile(&bytes, 0, key),
+                Err(_) => return Ok(None),
+            },
+        };
+
+        let Some(compartment) = vaultfile.find(key) else {
+            return Ok(None);
+        };
+        let (payload, manifest_len) = vaultfile.payload(compartment, key)?;
+        let payload = Payload::open(payload, manifest_len, key, seed)?;
+
+        Ok(Some(OpenedVault {
+            vaultfile,
+            compartment,
+            payload,
+        }))
+    }
+
+    // Write the payload back into the vaultfile. The vault is described with info, and its
+    // metadata is renewed if metadata_key is given, unless the payload is the hidden vault or a
+    // vault with a decoy. It is kept on disk as storage says.
+    // Chunks of removed or replaced files are dropped. The padding is kept as it is, a hidden
+    // vault may be at its end, so the slot does not shrink until the vault is locked again.
+    pub fn save(
+        mut self,
+        path: &Path,
+        key: &[u8],
+        info: &VaultInfo,
+        metadata_key: Option<&[u8]>,
+        storage: Storage,
+    ) -> Result<(), String> {
+        self.payload.compact()?;
+        let (payload, manifest_len) = self.payload.seal(key);
+        self.vaultfile
+            .set_payload(self.compartment, &payload, manifest_len, key)?;
+
+        self.vaultfile.info = Some(info.clone());
+        let describes_payload =
+            self.compartment != Compartment::Hidden && !self.payload.manifest.has_decoy;
+        if let Some(metadata_key) = metadata_key.filter(|_| describes_payload) {
+            self.vaultfile.metadata =
+                Some(vault_metadata(&self.payload.manifest).seal(metadata_key));
+        }
+
+        write_vaultfile(path, &self.vaultfile, storage)
+    }
+}
+
+// Returns true if the key opens the vaultfile kept while the vault is unlocked.
+pub fn retained_vaultfile_opens(path: &Path, key: &[u8]) -> bool {
+    let Ok(bytes) = fs::read(path.join(RETAINED_VAULTFILE)) else {
+        return false;
+    };
+
+    match Vaultfile::from_bytes(&bytes) {
+        Ok(Some(vaultfile)) => {
+            vaultfile.find_slot(key).is_some() || vaultfile.hidden_region(key).is_some()
+        }
+        _ => false,
+    }
+}
+
+// Returns true if the key opens a slot of the vaultfile in a directory, or of the one kept while
+// the vault is unlocked. That is the vault or its decoy, never its hidden vault.
+pub fn vault_slot_opens(path: &Path, key: &[u8]) -> bool {
+    let bytes = if volume::has_vaultfile(path) {
+        volume::read_vaultfile(path).ok()
+    } else {
+        fs::read(path.join(RETAINED_VAULTFILE)).ok()
+    };
+
+    match bytes.map(|bytes| Vaultfile::from_bytes(&bytes)) {
+        Some(Ok(Some(vaultfile))) => vaultfile.find_slot(key).is_some(),
+        _ => false,
+    }
+}
+
+// Read the description of the vault in a directory from its vaultfile.
+// Also returns whether the vault is locked, an unlocked vault only has the retained vaultfile.
+pub fn read_vault_info(path: &Path) -> Result<(VaultInfo, bool), String> {
+    let (vaultfile_path, is_locked) = if volume::has_vaultfile(path) {
+        (volume::header_path(path), true)
+    } else if path.join(RETAINED_VAULTFILE).is_file() {
+        (path.join(RETAINED_VAULTFILE), false)
+    } else {
+        return Err("The directory has no vaultfile!".to_string());
+    };
+
+    match vaultfile::read_info(&vaultfile_path)? {
+        Some(info) => Ok((info, is_locked)),
+        None => Err(
+            "The vaultfile does not describe its vault. Vaults have to be locked once by this \
+             version of the app before they can be imported."
+                .to_string(),
+        ),
+    }
+}
+
+// Put the files of hidden_dir into the padding of a locked vault as a hidden vault.
+// The files in hidden_dir are left in place.
+pub fn set_hidden_util(
+    path: &str,
+    key: &[u8],
+    hidden_key: &[u8],
+    hidden_dir: &str,
+    compression_level: i32,
+    storage: Storage,
+) -> Result<(), String> {
+    let path = Path::new(path);
+    let mut vaultfile = read_locked_vaultfile(path, key)?;
+    if vaultfile.find_slot(hidden_key).is_some() {
+        return Err("The hidden password already opens this vault or its decoy!".to_string());
+    }
+
+    let payload = encrypt_directory(hidden_dir, hidden_key, compression_level)?;
+    let (payload, manifest_len) = payload.seal(hidden_key);
+    vaultfile.seal_hidden(&payload, manifest_len, hidden_key)?;
+
+    write_vaultfile(path, &vaultfile, storage)
+}
+
+// Encrypt the files of a directory into a payload, without touching them.
+pub fn encrypt_directory(dir: &str, key: &[u8], compression_level: i32) -> Result<Payload, String> {
+    let entries = read_entries(Path::new(dir))?;
+    build_payload(&entries, key, None, compression_level)
+}
+
+// Put a payload encrypted with decoy_key into the slot next to the vault. The vault must be
+// locked. From then on its metadata describes the decoy, as that is what the duress password
+// restores.
+pub fn set_decoy_util(
+    path: &str,
+    key: &[u8],
+    decoy_key: &[u8],
+    decoy: &Payload,
+    info: &VaultInfo,
+    metadata_key: &[u8],
+    storage: Storage,
+) -> Result<(), String> {
+    let path = Path::new(path);
+    let (mut opened, index) = open_vault_slot(path, key, info)?;
+
+    let (payload, manifest_len) = decoy.seal(decoy_key);
+    opened.vaultfile.set_payload(
+        Compartment::Slot(1 - index),
+        &payload,
+        manifest_len,
+        decoy_key,
+    )?;
+    opened.vaultfile.metadata = Some(vault_metadata(&decoy.manifest).seal(metadata_key));
+    opened.payload.manifest.has_decoy = true;
+
+    opened.save(path, key, info, Some(metadata_key), storage)
+}
+
+// Replace the decoy of a locked vault with random filler of the same size. The metadata
+// describes the vault itself again.
+pub fn remove_decoy_util(
+    path: &str,
+    key: &[u8],
+    info: &VaultInfo,
+    metadata_key: &[u8],
+    storage: Storage,
 ) -> Result<(), String> {
-    // Shuffle back
-    let seed = calculate_seed(path.to_str().unwrap());
-    // let mut rng = StdRng::seed_from_u64(seed);
-    // plaintext_bytes.shuffle(&mut rng);
-    unshuffle_bytes(&mut plaintext_bytes, seed);
-
-    let file_count = plaintext_bytes[0] as usize;
-
-    let sizes = plaintext_bytes[1..=file_count].to_vec();
-    let data = plaintext_bytes[file_count + 1..].to_vec();
-
-    let mut bytes_read: usize = 0;
-    let mut data_read: usize = 0;
-    while bytes_read < sizes.len() {
-        let size_bytes = sizes[bytes_read..bytes_read + 8].try_into().unwrap();
-        let size = u64::from_le_bytes(size_bytes) as usize;
-
-        // Read size as many bytes into a vector
-        let mut file_bytes: Vec<u8> = vec![];
-        file_bytes.extend_from_slice(&data[data_read..data_read + size]);
-
-        // file_bytes now looks like
-        // name_size b1 b2 b3 b4 b5 contents
-        let (name_bytes, data_bytes) = file_bytes.split_at((file_bytes[0] + 1) as usize);
-        let name = String::from_utf8(name_bytes.to_vec())
-            .expect("Could not convert from name bytes into utf8 string.");
-
-        // Construct a file out of these bytes
-        let mut file = fs::File::create(format!("{}/{}", path.to_str().unwrap(), name))
-            .expect("Could not create a file for decrypted data!");
-        if let Err(e) = file.write_all(data_bytes) {
-            return Err(format!(
-                "Error writing plaintext bytes to new file: {}",
-                e.to_string()
-            ));
+    let path = Path::new(path);
+    let (mut opened, index) = open_vault_slot(path, key, info)?;
+
+    let other = &mut opened.vaultfile.slots[1 - index];
+    *other = filler_slot(other.len());
+    opened.payload.manifest.has_decoy = false;
+
+    opened.save(path, key, info, Some(metadata_key), storage)
+}
+
+// Open the slot of a locked vault the key opens, with the index of that slot. Hidden vaults
+// have no slot.
+fn open_vault_slot(
+    path: &Path,
+    key: &[u8],
+    info: &VaultInfo,
+) -> Result<(OpenedVault, usize), String> {
+    let seed = info
+        .seed
+        .unwrap_or_else(|| calculate_seed(path.to_str().unwrap()));
+    match OpenedVault::open(path, key, seed)? {
+        Some(opened) => match opened.compartment {
+            Compartment::Slot(index) => Ok((opened, index)),
+            Compartment::Hidden => Err("The key does not open this vault!".to_string()),
+        },
+        None => Err("The key does not open this vault!".to_string()),
+    }
+}
+
+// Read the vaultfile of a locked vault. Vaultfiles in the old layout are moved into a slot.
+fn read_locked_vaultfile(path: &Path, key: &[u8]) -> Result<Vaultfile, String> {
+    let bytes = volume::read_vaultfile(path)?;
+
+    if let Some(vaultfile) = Vaultfile::from_bytes(&bytes)? {
+        return Ok(vaultfile);
+    }
+
+    if decrypt_file(&bytes, key).is_err() {
+        return Err("The key does not open this vault!".to_string());
+    }
+
+    // The old layout has no manifest.
+    Ok(new_vaultfile(&bytes, 0, key))
+}
+
+// A vaultfile with the payload in one slot, random filler in the other and random padding.
+fn new_vaultfile(payload: &[u8], manifest_len: usize, key: &[u8]) -> Vaultfile {
+    let slot = seal_slot(payload, manifest_len, key, slot_len(payload.len()));
+    let filler = filler_slot(slot.len());
+
+    let mut vaultfile = Vaultfile {
+        info: None,
+        metadata: None,
+        slots: [slot, filler],
+        padding: vec![],
+    };
+    vaultfile.shuffle_slots();
+    vaultfile
+        .fill_padding(None)
+        .expect("Padding without a hidden vault always fits.");
+    vaultfile
+}
+
+// Write the vaultfile, split into volumes if storage says so, and renew its parity.
+fn write_vaultfile(path: &Path, vaultfile: &Vaultfile, storage: Storage) -> Result<(), String> {
+    let bytes = vaultfile.to_volume_bytes(storage.volume_size, storage.header_key.as_ref());
+    volume::write_vaultfile(path, &bytes, storage.volume_size)?;
+
+    write_parity(path, &bytes, storage.parity_redundancy)
+}
+
+// Write the vaultfile of a locked vault again to match storage, such as after its volume size
+// changed. A header that does not authenticate is not written again, that would make it look
+// authentic.
+pub fn rewrite_vaultfile(path: &Path, storage: Storage) -> Result<(), String> {
+    let header = vaultfile::read_header(&volume::header_path(path))?;
+    if let (Some(header), Some(key)) = (header, storage.header_key) {
+        if !header.authenticates(&key).unwrap_or(!storage.header_mac) {
+            return Err("The header of the vaultfile does not authenticate!".to_string());
         }
+    }
 
-        bytes_read += 8;
-        data_read += size;
+    let bytes = volume::read_vaultfile(path)?;
+    match Vaultfile::from_bytes(&bytes)? {
+        Some(vaultfile) => write_vaultfile(path, &vaultfile, storage),
+        None => Err(
+            "The vaultfile is in the old layout. Lock the vault once with this version of the \
+             app first."
+                .to_string(),
+        ),
     }
+}
 
-    Ok(())
+// Keep the vaultfile of a vault that was unlocked as RETAINED_VAULTFILE. Volumes are put back
+// together, the vaultfile is split again when the vault is locked.
+pub fn retain_vaultfile(path: &Path) -> Result<(), String> {
+    if path.join("vaultfile").is_file() {
+        return match fs::rename(path.join("vaultfile"), path.join(RETAINED_VAULTFILE)) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Error retaining vaultfile after decryption: {}", e)),
+        };
+    }
+
+    let bytes = volume::read_vaultfile(path)?;
+    if let Err(e) = write_atomic(&path.join(RETAINED_VAULTFILE), &bytes) {
+        return Err(format!("Error retaining vaultfile after decryption: {}", e));
+    }
+    volume::remove_volumes(path, 1)
+}
+
+// The entries of a directory that go into a vault. Reserved names are left out.
+fn read_entries(path: &Path) -> Result<Vec<DirEntry>, String> {
+    match path.read_dir() {
+        Ok(read_dir) => Ok(read_dir
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_name().to_str().is_some_and(is_reserved_name))
+            .collect()),
+        Err(e) => Err(format!("Could not read the directory: {}", e)),
+    }
+}
+
+// Encrypt the files of a directory into a payload. Chunks that are in the previous payload
+// are copied from it instead of being encrypted again.
+fn build_payload(
+    entries: &[DirEntry],
+    key: &[u8],
+    previous: Option<&Payload>,
+    compression_level: i32,
+) -> Result<Payload, String> {
+    let mut payload = Payload::default();
+    payload.compression_level = compression_level;
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_str().ok_or("A file name is not valid utf8!")?;
+        let (contents, modified) = read_file(&entry.path())?;
+        payload.add_file(name, &contents, modified, key, previous)?;
+    }
+
+    Ok(payload)
+}
+
+// Encrypt a file into a payload under its file name, replacing a file of the same name.
+pub fn add_path(payload: &mut Payload, path: &Path, key: &[u8]) -> Result<(), String> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("A file name is not valid utf8!")?;
+    add_path_as(payload, name, path, key)
+}
+
+// Encrypt a file into a payload under the given name, replacing a file of that name.
+pub fn add_path_as(
+    payload: &mut Payload,
+    name: &str,
+    path: &Path,
+    key: &[u8],
+) -> Result<(), String> {
+    let (contents, modified) = read_file(path)?;
+    payload.add_file(name, &contents, modified, key, None)
+}
+
+// Read a file that goes into a vault with its modification time.
+fn read_file(path: &Path) -> Result<(Plaintext, Option<u64>), String> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return Err(format!("Could not extract metadata from file: {}", e)),
+    };
+    if !metadata.is_file() {
+        return Err(format!("{} is not a file!", path.display()));
+    }
+
+    let contents = match fs::read(path) {
+        Ok(contents) => Plaintext::new(contents),
+        Err(e) => return Err(format!("Could not read file bytes: {}", e)),
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs());
+
+    Ok((contents, modified))
+}
+
+// Write the files of a payload into the directory.
+// Returns how each file compares with the hash it was locked with.
+pub fn restore_files(
+    payload: &Payload,
+    key: &[u8],
+    path: &Path,
+) -> Result<Vec<(String, Checksum)>, String> {
+    let mut checks = vec![];
+    for file in &payload.manifest.files {
+        checks.push((
+            file.name.to_string(),
+            restore_file(payload, file, key, path)?,
+        ));
+    }
+
+    Ok(checks)
+}
+
+// Write a single file of a payload into the directory. Only the chunks of that file are
+// decrypted. The file is read back once it is on disk and compared with the hash it was locked
+// with.
+pub fn restore_file(
+    payload: &Payload,
+    file: &FileEntry,
+    key: &[u8],
+    path: &Path,
+) -> Result<Checksum, String> {
+    if !is_safe_name(&file.name) || is_reserved_name(&file.name) {
+        return Err(format!("Refusing to restore a file named {:?}!", file.name));
+    }
+
+    let contents = payload.read_file(file, key)?;
+    let mut out = match fs::File::create(path.join(&file.name)) {
+        Ok(out) => out,
+        Err(e) => return Err(format!("Could not create a file for decrypted data: {}", e)),
+    };
+    if let Err(e) = out.write_all(&contents) {
+        return Err(format!("Error writing plaintext bytes to new file: {}", e));
+    }
+
+    if let Some(modified) = file.modified {
+        if let Err(e) = out.set_modified(UNIX_EPOCH + Duration::from_secs(modified)) {
+            return Err(format!("Could not set the modification time: {}", e));
+        }
+    }
+    if let Err(e) = out.sync_all() {
+        return Err(format!("Could not flush the restored file to disk: {}", e));
+    }
+
+    let written = match fs::read(path.join(&file.name)) {
+        Ok(written) => Plaintext::new(written),
+        Err(e) => return Err(format!("Could not read back the restored file: {}", e)),
+    };
+    Ok(file.check(&written, key))
 }
 
 // Function to add the vault of the given properties into the Configfile
 pub fn append_to_vaults(
-    name: &str,
     path: &str,
+    name: &str,
     hash: &str,
-    salt: SaltString,
+    info: &VaultInfo,
+    metadata_key: &str,
     handle: tauri::AppHandle,
 ) {
     // TODO: Implement checking for existing vaults
     match Config::from_json(handle.clone()) {
         Ok(mut config) => {
-            config.append_new(path, name, hash, salt.as_str(), true);
+            config.append_new(path, name, hash, info, true);
+            let index = config.vaults.len() - 1;
+            config.record_created(index);
+            config.vaults[index].metadata_key = metadata_key.to_string();
+            config.record_header_mac(index);
             config
                 .to_json(handle)
                 .expect("Could not convert the updated Config file.");
@@ -116,13 +609,25 @@ pub fn append_to_vaults(
 pub fn generate_hash_salt(argon2: &Argon2, password: &str) -> (String, SaltString) {
     let salt = SaltString::generate(&mut OsRng);
     let hash = argon2
-        .hash_password(password.to_string().as_bytes(), &salt)
+        .hash_password(password.as_bytes(), &salt)
         .unwrap()
         .to_string();
 
     (hash, salt)
 }
 
+// Hash a password with the salt a vault already has, such as one read from its vaultfile.
+pub fn hash_with_salt(argon2: &Argon2, password: &str, salt: &str) -> Result<String, String> {
+    let salt = match SaltString::from_b64(salt) {
+        Ok(salt) => salt,
+        Err(e) => return Err(format!("The salt of the vault is not valid: {}", e)),
+    };
+    match argon2.hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(format!("Could not hash the password: {}", e)),
+    }
+}
+
 pub fn verify_password(argon2: &Argon2, hash: String, password: &str) -> bool {
     let parsed_hash =
         PasswordHash::new(&hash).expect("Could not parse password hash for verification.");
@@ -132,33 +637,33 @@ pub fn verify_password(argon2: &Argon2, hash: String, password: &str) -> bool {
 }
 
 // Use Argon2 to derive a key from hash and salt
-pub fn derive_key<'a>(
-    argon2: Argon2<'a>,
-    password: &'a str,
-    salt: &'a str,
-    key_bytes: &'a mut [u8],
-) {
-    if let Err(e) = argon2.hash_password_into(password.as_bytes(), salt.as_bytes(), key_bytes) {
+pub fn derive_key(argon2: Argon2, password: &str, salt: &str) -> SecretKey {
+    let mut key = SecretKey::new();
+    if let Err(e) = argon2.hash_password_into(password.as_bytes(), salt.as_bytes(), &mut key) {
         panic!("Error deriving a key: {}", e);
     }
+    key
 }
 
 // Decrypt a file using the generated key
-pub fn decrypt_file(file: Vec<u8>, key: &[u8]) -> Vec<u8> {
+pub fn decrypt_file(file: &[u8], key: &[u8]) -> Result<Plaintext, String> {
     let aes_key = Key::<Aes256Gcm>::from_slice(key);
     let cipher = Aes256Gcm::new(aes_key);
 
+    if file.len() < 12 {
+        return Err("Failed to decrypt the file: it is too short!".to_string());
+    }
     let (nonce_bytes, ciphertext) = file.split_at(12); // First 12 bytes is the nonce
     let nonce = Nonce::from_slice(nonce_bytes);
 
-    let plaintext = cipher
-        .decrypt(nonce, ciphertext)
-        .expect("Failed to decrypt the file!");
-    plaintext
+    match cipher.decrypt(nonce, ciphertext) {
+        Ok(plaintext) => Ok(Plaintext::new(plaintext)),
+        Err(_) => Err("Failed to decrypt the file!".to_string()),
+    }
 }
 
 // Encrypt a file using the generated key. Use Aes256 with nonce.
-fn encrypt_file(file: &[u8], key: &[u8]) -> Vec<u8> {
+pub fn encrypt_file(file: &[u8], key: &[u8]) -> Vec<u8> {
     let aes_key = Key::<Aes256Gcm>::from_slice(key);
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
     let cipher = Aes256Gcm::new(aes_key);
@@ -178,11 +683,15 @@ fn encrypt_file(file: &[u8], key: &[u8]) -> Vec<u8> {
 // Convert the path to a byte array, get first 8 and last 8 bytes as u64 and add them
 // Take the power of 42.
 // In the real world use something more secure.
-fn calculate_seed(path: &str) -> u64 {
-    let bytes = path.as_bytes().to_vec();
+pub fn calculate_seed(path: &str) -> u64 {
+    // Paths shorter than 8 bytes are padded with zeros.
+    let mut bytes = path.as_bytes().to_vec();
+    if bytes.len() < 8 {
+        bytes.resize(8, 0);
+    }
     let first_8 = u64::from_le_bytes(bytes[..8].try_into().unwrap());
 
-    let start = if bytes.len() >= 8 { bytes.len() - 8 } else { 0 };
+    let start = bytes.len() - 8;
     let last_8 = u64::from_le_bytes(bytes[start..].try_into().unwrap());
     // let last_8 = u64::from_le_bytes(bytes[8..].try_into().unwrap());
     let sum = first_8.wrapping_add(last_8);
@@ -191,23 +700,8 @@ fn calculate_seed(path: &str) -> u64 {
     seed
 }
 
-// Shuffle the given bytes in place using the given seed
-fn shuffle_bytes(bytes: &mut Vec<u8>, seed: u64) {
-    let mut rng = StdRng::seed_from_u64(seed);
-    let indices: Vec<usize> = (0..bytes.len()).collect();
-    let mut shuffle_map: Vec<_> = indices.clone();
-    shuffle_map.shuffle(&mut rng);
-
-    let mut shuffled = bytes.clone();
-    for (i, &idx) in shuffle_map.iter().enumerate() {
-        shuffled[i] = bytes[idx];
-    }
-
-    *bytes = shuffled;
-}
-
 // Unshuffle the bytes in place using the given seed
-fn unshuffle_bytes(bytes: &mut Vec<u8>, seed: u64) {
+pub fn unshuffle_bytes(bytes: &mut Plaintext, seed: u64) {
     let mut rng = StdRng::seed_from_u64(seed);
     let indices: Vec<usize> = (0..bytes.len()).collect();
     let mut shuffle_map: Vec<_> = indices;
@@ -218,62 +712,116 @@ fn unshuffle_bytes(bytes: &mut Vec<u8>, seed: u64) {
         unshuffled[idx] = bytes[i];
     }
 
-    *bytes = unshuffled;
+    // The old bytes are zeroed when unshuffled is dropped.
+    std::mem::swap(bytes, &mut unshuffled);
 }
 
-// Create bytes of vaultfile from a list of entries
-fn create_vaultfile_bytes(entries: &Vec<DirEntry>, seed: u64) -> Result<Vec<u8>, String> {
-    let mut vaultfile_bytes = vec![];
+// Statistics about the files in a vault.
+fn vault_metadata(manifest: &Manifest) -> VaultMetadata {
+    let params = Argon2::default().params().clone();
+    VaultMetadata {
+        file_count: manifest.files.len() as u64,
+        plaintext_size: manifest.files.iter().map(|file| file.size).sum(),
+        cipher: "AES-256-GCM".to_string(),
+        kdf: format!(
+            "Argon2id (m={}, t={}, p={})",
+            params.m_cost(),
+            params.t_cost(),
+            params.p_cost()
+        ),
+    }
+}
 
-    // The size of each file will take 8 bytes
-    vaultfile_bytes.push((entries.len() * 8) as u8);
+// Overwrite a file with random bytes and flush it to disk before removing it.
+pub fn secure_delete(path: &Path) -> Result<(), String> {
+    let len = match fs::metadata(path) {
+        Ok(metadata) => metadata.len() as usize,
+        Err(e) => return Err(format!("Could not read metadata of file to delete: {}", e)),
+    };
+
+    let mut noise = vec![0u8; len];
+    OsRng.fill_bytes(&mut noise);
+
+    let mut file = match fs::OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("Could not open file to delete: {}", e)),
+    };
+    if let Err(e) = file.write_all(&noise).and_then(|_| file.sync_all()) {
+        return Err(format!("Could not overwrite file before deleting: {}", e));
+    }
 
-    // Place the sizes in order
-    for entry in entries {
-        let metadata = fs::metadata(entry.path()).expect("Could not extract metadata from file!");
-        // let size_bytes = metadata.len().to_le_bytes();
+    if let Err(e) = fs::remove_file(path) {
+        return Err(format!("Could not remove overwritten file: {}", e));
+    }
 
-        // Size will be the file size + the size of the name + 1 more byte indicating the size of the name
-        let file_size = metadata.len();
-        let name_size = entry.file_name().to_str().unwrap().len() as u64;
-        let total_size = file_size + name_size + 1;
+    Ok(())
+}
 
-        let size_bytes = total_size.to_le_bytes();
-        vaultfile_bytes.extend_from_slice(&size_bytes);
-    }
+// Write a file so a crash at any point leaves either the old or the new contents on disk.
+// The contents go to a temporary file that is flushed and then renamed over the file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = write_temp(path, contents)?;
+    replace_with_temp(&tmp_path, path)
+}
 
-    // Merge bytes of files into vaultfile_bytes
-    for entry in entries {
-        let file_bytes = fs::read(entry.path()).expect("Could not read file bytes!");
+// Write the contents meant for path into a temporary file next to it and flush it to disk.
+// Being a dotfile, it is never locked into a vault if a crash leaves it behind.
+// Returns the path of the temporary file.
+pub fn write_temp(path: &Path, contents: &[u8]) -> io::Result<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{}.tmp", name));
 
-        // Place the size of the name before the contents
-        let name = entry.file_name();
+    let mut tmp = fs::File::create(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
 
-        let name_size = name.to_str().unwrap().len() as u8;
-        vaultfile_bytes.push(name_size);
+    Ok(tmp_path)
+}
 
-        // Place the name bytes beofre the contents
-        let name_bytes = name.to_str().unwrap().as_bytes();
-        vaultfile_bytes.extend_from_slice(name_bytes);
+// Rename a temporary file from write_temp over the file it was written for.
+pub fn replace_with_temp(tmp_path: &Path, path: &Path) -> io::Result<()> {
+    fs::rename(tmp_path, path)?;
 
-        // Place the file contents
-        vaultfile_bytes.extend_from_slice(&file_bytes);
+    // Flush the rename itself. Directories cannot be opened like this on Windows.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
 
-        // Remove the plaintext file after adding its bytes to the vault.
-        if let Err(e) = fs::remove_file(entry.path()) {
-            return Err(format!("Error removing plaintext file: {}", e.to_string()));
-        }
+    Ok(())
+}
+
+// Resolve symlinks, "." and ".." and trailing slashes, so a directory has one path in the
+// configfile however it was picked.
+pub fn canonical_path(path: &str) -> Result<String, String> {
+    match fs::canonicalize(path) {
+        Ok(path) => Ok(path
+            .to_str()
+            .ok_or("The path is not valid utf8!")?
+            .to_string()),
+        Err(e) => Err(format!("Could not resolve the path {}: {}", path, e)),
     }
+}
 
-    // Shuffle the bytes in plaintext for more security
-    // let mut rng = StdRng::seed_from_u64(seed);
-    // vaultfile_bytes.shuffle(&mut rng);
-    shuffle_bytes(&mut vaultfile_bytes, seed);
+// Seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
 
-    Ok(vaultfile_bytes)
+// Returns true if files of this name are never locked into a vault: dotfiles, such as the
+// retained vaultfile, and whatever is named like the vaultfile or one of its volumes.
+// Restoring one of those would overwrite the vaultfile of the vault it came from.
+pub fn is_reserved_name(name: &str) -> bool {
+    name.starts_with('.') || volume::is_vaultfile_name(name)
 }
 
-// Returns true if the name of the file starts with a dot.
-fn is_dotfile(entry: &DirEntry) -> bool {
-    entry.file_name().to_str().unwrap().starts_with(".")
+// An empty directory of its own for a test.
+#[cfg(test)]
+pub fn test_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("secureme-test-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
 }
@@ -0,0 +1,564 @@
+// Layout of a vaultfile:
+//
+// magic | version | header length | header json | slot | slot | padding
+//
+// The header also describes the vault, so a vaultfile can be added back to the app if the
+// configfile is lost. Vaultfiles locked before this have no description. Statistics about the
+// vault's contents follow, encrypted with a metadata key from the configfile, so they can be
+// shown without the password. The header is authenticated with a key derived from the
+// metadata key, so a header that was changed outside the app can be told apart.
+//
+// Every vaultfile has two slots. One holds the vault, the other holds either a decoy vault
+// or random filler, so a vaultfile with a decoy cannot be told apart from one without.
+// A slot starts with a small encrypted header holding the length of its payload and of the
+// manifest at the payload's end. This lets a key find its slot without decrypting the whole
+// payload. The rest of the slot is the
+// payload followed by random bytes up to the size of the slot.
+//
+// The padding is random bytes whose size only depends on the size of the slots. A hidden vault
+// lives at the end of the padding: its payload followed by a slot header. Without the hidden
+// password those bytes cannot be told apart from the rest of the padding.
+//
+// A vaultfile may be split into volumes of a fixed size. The header of the first volume says
+// how many there are, so a missing one is found by its number.
+//
+// Vaultfiles written before this layout have no magic and are a single encrypted payload.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::Read, path::Path};
+
+use crate::utils::{decrypt_file, encrypt_file};
+
+const MAGIC: &[u8; 4] = b"SMVF";
+const VERSION: u8 = 1;
+
+// Nonce, 16 bytes of plaintext and the authentication tag.
+const SLOT_HEADER_LEN: usize = 12 + 16 + 16;
+
+const MIN_PADDING: usize = 64 * 1024;
+
+const HEADER_KEY_CONTEXT: &str = "SecureMe 2024 vaultfile header";
+
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    slots: [u64; 2],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault: Option<VaultInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<Volumes>,
+    // Keyed hash of the rest of the header, hex encoded. Missing for headers written before
+    // they were authenticated or without a header key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+}
+
+// How a vaultfile is split into volumes. Every volume but the last holds size bytes.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Volumes {
+    pub count: u32,
+    pub size: u64,
+}
+
+// What importing a vault needs to know about it, kept in the header of its vaultfile. Anyone
+// can read the header, so the name of the vault and the hash of its password are not in it.
+// Importing asks for both.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VaultInfo {
+    pub id: String,
+    pub salt: String,
+    // Missing for vaultfiles described before the seed was kept. Those were shuffled with a
+    // seed from their path.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+// Statistics about the contents of a vault. They describe what the decoy password restores if
+// the vault has a decoy and the vault itself otherwise, never its hidden vault. Anyone who can
+// read the configfile can read them.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultMetadata {
+    pub file_count: u64,
+    pub plaintext_size: u64,
+    pub cipher: String,
+    pub kdf: String,
+}
+
+// The part of a vaultfile a key opens.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compartment {
+    Slot(usize),
+    Hidden,
+}
+
+pub struct Vaultfile {
+    pub info: Option<VaultInfo>,
+    // Sealed VaultMetadata, hex encoded.
+    pub metadata: Option<String>,
+    pub slots: [Vec<u8>; 2],
+    pub padding: Vec<u8>,
+}
+
+impl Vaultfile {
+    // Parse the bytes of a vaultfile. Returns None for vaultfiles in the old layout.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, String> {
+        if !bytes.starts_with(MAGIC) {
+            return Ok(None);
+        }
+
+        let truncated = || "The vaultfile is truncated!".to_string();
+        let version = *bytes.get(4).ok_or_else(truncated)?;
+        if version != VERSION {
+            return Err(format!("Unsupported vaultfile version: {}", version));
+        }
+
+        let len_bytes = bytes.get(5..9).ok_or_else(truncated)?;
+        let header_len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+        let header_bytes = bytes.get(9..9 + header_len).ok_or_else(truncated)?;
+        let header: Header = match serde_json::from_slice(header_bytes) {
+            Ok(header) => header,
+            Err(e) => return Err(format!("Could not parse the vaultfile header: {}", e)),
+        };
+
+        let first_start = 9 + header_len;
+        let second_start = first_start + header.slots[0] as usize;
+        let end = second_start + header.slots[1] as usize;
+        let first = bytes.get(first_start..second_start).ok_or_else(truncated)?;
+        let second = bytes.get(second_start..end).ok_or_else(truncated)?;
+
+        Ok(Some(Vaultfile {
+            info: header.vault,
+            metadata: header.metadata,
+            slots: [first.to_vec(), second.to_vec()],
+            padding: bytes[end..].to_vec(),
+        }))
+    }
+
+    // The bytes of the vaultfile. Its header is authenticated with header_key if one is given.
+    pub fn to_bytes(&self, header_key: Option<&[u8; 32]>) -> Vec<u8> {
+        self.encode(None, header_key)
+    }
+
+    // The bytes of the vaultfile with the volumes of volume_size it is split into written to
+    // its header. A volume_size of 0 or a vaultfile that fits into one volume is not split.
+    pub fn to_volume_bytes(&self, volume_size: u64, header_key: Option<&[u8; 32]>) -> Vec<u8> {
+        let mut bytes = self.to_bytes(header_key);
+        if volume_size == 0 {
+            return bytes;
+        }
+
+        // The count makes the header longer, which may take another volume.
+        let mut count = 1;
+        while bytes.len() as u64 > count * volume_size {
+            count = (bytes.len() as u64).div_ceil(volume_size);
+            bytes = self.encode(
+                Some(Volumes {
+                    count: count as u32,
+                    size: volume_size,
+                }),
+                header_key,
+            );
+        }
+        bytes
+    }
+
+    fn encode(&self, volumes: Option<Volumes>, header_key: Option<&[u8; 32]>) -> Vec<u8> {
+        let mut header = Header {
+            slots: [self.slots[0].len() as u64, self.slots[1].len() as u64],
+            vault: self.info.clone(),
+            metadata: self.metadata.clone(),
+            volumes,
+            mac: None,
+        };
+        header.mac = header_key.map(|key| header.mac_with(key).to_hex().to_string());
+        let header_bytes =
+            serde_json::to_vec(&header).expect("Could not convert vaultfile header to json.");
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&(header_bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header_bytes);
+        bytes.extend_from_slice(&self.slots[0]);
+        bytes.extend_from_slice(&self.slots[1]);
+        bytes.extend_from_slice(&self.padding);
+        bytes
+    }
+
+    // Returns the index of the slot the key opens, if any.
+    pub fn find_slot(&self, key: &[u8]) -> Option<usize> {
+        (0..self.slots.len()).find(|&i| open_header(&self.slots[i], key).is_some())
+    }
+
+    // Returns the compartment the key opens: one of the slots or the hidden vault.
+    pub fn find(&self, key: &[u8]) -> Option<Compartment> {
+        match self.find_slot(key) {
+            Some(index) => Some(Compartment::Slot(index)),
+            None => self.hidden_region(key).map(|_| Compartment::Hidden),
+        }
+    }
+
+    // Returns the encrypted payload of a compartment and the length of the manifest at its end.
+    pub fn payload(&self, compartment: Compartment, key: &[u8]) -> Result<(&[u8], usize), String> {
+        match compartment {
+            Compartment::Slot(index) => {
+                let slot = &self.slots[index];
+                let (len, manifest_len) =
+                    open_header(slot, key).ok_or("The key does not open this slot!")?;
+
+                let payload = slot
+                    .get(SLOT_HEADER_LEN..SLOT_HEADER_LEN + len)
+                    .ok_or("The slot is truncated!")?;
+                Ok((payload, manifest_len))
+            }
+            Compartment::Hidden => {
+                let region = self
+                    .hidden_region(key)
+                    .ok_or("The key does not open a hidden vault!")?;
+                let header_start = region.len() - SLOT_HEADER_LEN;
+                let (_, manifest_len) = open_header(&region[header_start..], key).unwrap();
+                Ok((&region[..header_start], manifest_len))
+            }
+        }
+    }
+
+    // Replace the payload of a compartment. A slot never shrinks, its size was already seen and
+    // the padding that is kept was sized for it. Slots only shrink when locking renews the
+    // padding.
+    pub fn set_payload(
+        &mut self,
+        compartment: Compartment,
+        payload: &[u8],
+        manifest_len: usize,
+        key: &[u8],
+    ) -> Result<(), String> {
+        match compartment {
+            Compartment::Slot(index) => {
+                let len = slot_len(payload.len()).max(self.slots[index].len());
+                self.slots[index] = seal_slot(payload, manifest_len, key, len);
+                self.fit_padding();
+                Ok(())
+            }
+            Compartment::Hidden => self.seal_hidden(payload, manifest_len, key),
+        }
+    }
+
+    // Returns the hidden vault the key opens as it is stored at the end of the padding,
+    // its payload followed by its header.
+    pub fn hidden_region(&self, key: &[u8]) -> Option<&[u8]> {
+        let header_start = self.padding.len().checked_sub(SLOT_HEADER_LEN)?;
+        let (len, _) = open_header(&self.padding[header_start..], key)?;
+        let start = header_start.checked_sub(len)?;
+
+        Some(&self.padding[start..])
+    }
+
+    // Room for a hidden vault's encrypted payload in the padding.
+    pub fn hidden_capacity(&self) -> usize {
+        self.padding.len().saturating_sub(SLOT_HEADER_LEN)
+    }
+
+    // Replace the padding with new random bytes. A hidden vault passed in is kept at the end.
+    // The padding is always sized by the slots, a larger one would give the hidden vault away.
+    // Fails if the hidden vault does not fit.
+    pub fn fill_padding(&mut self, hidden: Option<&[u8]>) -> Result<(), String> {
+        let hidden = hidden.unwrap_or_default();
+        let len = padding_len(self.slots_len());
+        if hidden.len() > len {
+            return Err(format!(
+                "The hidden vault needs {} bytes but the vault now only has room for {}. \
+                 Remove files from the hidden vault or keep more files in the vault.",
+                hidden.len(),
+                len
+            ));
+        }
+
+        let mut padding = vec![0u8; len - hidden.len()];
+        OsRng.fill_bytes(&mut padding);
+        padding.extend_from_slice(hidden);
+        self.padding = padding;
+        Ok(())
+    }
+
+    // Grow the padding after the slots grew, keeping any hidden vault at its end.
+    pub fn fit_padding(&mut self) {
+        let len = padding_len(self.slots_len());
+        if self.padding.len() < len {
+            let mut padding = vec![0u8; len - self.padding.len()];
+            OsRng.fill_bytes(&mut padding);
+            padding.extend_from_slice(&self.padding);
+            self.padding = padding;
+        }
+    }
+
+    // Write a hidden vault to the end of the padding. The payload must fit the hidden capacity.
+    pub fn seal_hidden(
+        &mut self,
+        payload: &[u8],
+        manifest_len: usize,
+        key: &[u8],
+    ) -> Result<(), String> {
+        if payload.len() > self.hidden_capacity() {
+            return Err(format!(
+                "The hidden vault needs {} bytes but this vault only has room for {}!",
+                payload.len(),
+                self.hidden_capacity()
+            ));
+        }
+
+        let start = self.hidden_capacity() - payload.len();
+        self.padding[start..start + payload.len()].copy_from_slice(payload);
+        self.padding[start + payload.len()..].copy_from_slice(&seal_header(
+            payload.len(),
+            manifest_len,
+            key,
+        ));
+
+        Ok(())
+    }
+
+    fn slots_len(&self) -> usize {
+        self.slots[0].len() + self.slots[1].len()
+    }
+
+    // Put the slots in a random order so their position does not tell which one was written last.
+    pub fn shuffle_slots(&mut self) {
+        if OsRng.next_u32() % 2 == 1 {
+            self.slots.swap(0, 1);
+        }
+    }
+}
+
+impl Header {
+    // Whether the header was written with the given key and not changed since. None if it was
+    // written without one.
+    pub fn authenticates(&self, key: &[u8; 32]) -> Option<bool> {
+        let mac = self.mac.as_ref()?;
+        Some(blake3::Hash::from_hex(mac).is_ok_and(|mac| mac == self.mac_with(key)))
+    }
+
+    fn mac_with(&self, key: &[u8; 32]) -> blake3::Hash {
+        let unauthenticated = Header {
+            slots: self.slots,
+            vault: self.vault.clone(),
+            metadata: self.metadata.clone(),
+            volumes: self.volumes,
+            mac: None,
+        };
+        let json = serde_json::to_vec(&unauthenticated)
+            .expect("Could not convert vaultfile header to json.");
+        blake3::keyed_hash(key, &json)
+    }
+}
+
+impl VaultMetadata {
+    pub fn seal(&self, key: &[u8]) -> String {
+        let json = serde_json::to_vec(self).expect("Could not convert vault metadata to json.");
+        hex::encode(encrypt_file(&json, key))
+    }
+
+    // Returns None if the metadata was not sealed with the key or was tampered with.
+    pub fn open(sealed: &str, key: &[u8]) -> Option<Self> {
+        let json = decrypt_file(&hex::decode(sealed).ok()?, key).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+// Read the description of a vault from the header of its vaultfile, without reading the slots.
+// Returns None for vaultfiles without a description.
+pub fn read_info(path: &Path) -> Result<Option<VaultInfo>, String> {
+    Ok(read_header(path)?.and_then(|header| header.vault))
+}
+
+// Read the header of a vaultfile. Returns None for vaultfiles in the old layout.
+pub fn read_header(path: &Path) -> Result<Option<Header>, String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return Err(format!("Could not open the vaultfile: {}", e)),
+    };
+
+    let mut start = [0u8; 9];
+    if file.read_exact(&mut start).is_err() || !start.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    if start[4] != VERSION {
+        return Err(format!("Unsupported vaultfile version: {}", start[4]));
+    }
+
+    let header_len = u32::from_le_bytes(start[5..9].try_into().unwrap()) as usize;
+    let mut header_bytes = vec![0u8; header_len];
+    if let Err(e) = file.read_exact(&mut header_bytes) {
+        return Err(format!("Could not read the vaultfile header: {}", e));
+    }
+
+    match serde_json::from_slice::<Header>(&header_bytes) {
+        Ok(header) => Ok(Some(header)),
+        Err(e) => Err(format!("Could not parse the vaultfile header: {}", e)),
+    }
+}
+
+// The key the header of a vault's vaultfile is authenticated with.
+pub fn header_key(metadata_key: &[u8]) -> [u8; 32] {
+    blake3::derive_key(HEADER_KEY_CONTEXT, metadata_key)
+}
+
+// Seal an encrypted payload into a slot of the given size.
+pub fn seal_slot(payload: &[u8], manifest_len: usize, key: &[u8], len: usize) -> Vec<u8> {
+    let mut slot = seal_header(payload.len(), manifest_len, key);
+    slot.extend_from_slice(payload);
+
+    let mut filler = vec![0u8; len.saturating_sub(slot.len())];
+    OsRng.fill_bytes(&mut filler);
+    slot.extend_from_slice(&filler);
+    slot
+}
+
+// A slot of random bytes that no key opens.
+pub fn filler_slot(len: usize) -> Vec<u8> {
+    let mut slot = vec![0u8; len];
+    OsRng.fill_bytes(&mut slot);
+    slot
+}
+
+// Round the size of a slot up to a size class, so its size only hints at the size of its payload.
+pub fn slot_len(payload_len: usize) -> usize {
+    let len = SLOT_HEADER_LEN + payload_len;
+    let step = (len.next_power_of_two() / 8).max(4096);
+    len.div_ceil(step) * step
+}
+
+// Size of the padding for slots of the given total size.
+pub fn padding_len(slots_len: usize) -> usize {
+    (slots_len / 8).max(MIN_PADDING).next_multiple_of(4096)
+}
+
+// The manifest length is 0 for payloads written before they had a manifest.
+fn seal_header(payload_len: usize, manifest_len: usize, key: &[u8]) -> Vec<u8> {
+    let mut header = [0u8; 16];
+    header[..8].copy_from_slice(&(payload_len as u64).to_le_bytes());
+    header[8..].copy_from_slice(&(manifest_len as u64).to_le_bytes());
+    encrypt_file(&header, key)
+}
+
+// Decrypt the header of a slot to get the length of its payload and of its manifest.
+fn open_header(slot: &[u8], key: &[u8]) -> Option<(usize, usize)> {
+    let header = decrypt_file(slot.get(..SLOT_HEADER_LEN)?, key).ok()?;
+    let len = u64::from_le_bytes(header[..8].try_into().ok()?);
+    let manifest_len = u64::from_le_bytes(header[8..].try_into().ok()?);
+    Some((len as usize, manifest_len as usize))
+}
+
+// A vaultfile of random filler that no key opens, for tests.
+#[cfg(test)]
+pub fn test_vaultfile() -> Vaultfile {
+    Vaultfile {
+        info: None,
+        metadata: None,
+        slots: [filler_slot(5000), filler_slot(5000)],
+        padding: filler_slot(3000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [1; 32];
+    const OTHER_KEY: [u8; 32] = [2; 32];
+    const HIDDEN_KEY: [u8; 32] = [3; 32];
+
+    // A vaultfile with the payload in its first slot, filler in the other and fresh padding.
+    fn vaultfile_with(payload: &[u8]) -> Vaultfile {
+        let mut vaultfile = test_vaultfile();
+        vaultfile.slots[0] = seal_slot(payload, 16, &KEY, slot_len(payload.len()));
+        vaultfile.slots[1] = filler_slot(vaultfile.slots[0].len());
+        vaultfile.fill_padding(None).unwrap();
+        vaultfile
+    }
+
+    #[test]
+    fn slots_and_padding_are_sized_by_class() {
+        assert_eq!(slot_len(0), 4096);
+        assert_eq!(slot_len(4096 - SLOT_HEADER_LEN), 4096);
+        assert_eq!(slot_len(4096 - SLOT_HEADER_LEN + 1), 8192);
+        for payload_len in [1, 5000, 100_000, 3_000_000] {
+            let len = slot_len(payload_len);
+            assert!(len >= payload_len + SLOT_HEADER_LEN);
+            assert_eq!(len % 4096, 0);
+            // Rounding up to a size class costs less than a quarter of the slot, or of a page.
+            assert!(len - (payload_len + SLOT_HEADER_LEN) < (len / 4).max(4096));
+        }
+
+        assert_eq!(padding_len(0), MIN_PADDING);
+        assert_eq!(padding_len(8 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(padding_len(8 * 1024 * 1024 + 8), 1024 * 1024 + 4096);
+    }
+
+    #[test]
+    fn slot_reads_back() {
+        let payload = filler_slot(5000);
+        let vaultfile = vaultfile_with(&payload);
+        let parsed = Vaultfile::from_bytes(&vaultfile.to_bytes(None))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(parsed.find(&KEY), Some(Compartment::Slot(0)));
+        assert_eq!(parsed.find(&OTHER_KEY), None);
+        let (read, manifest_len) = parsed.payload(Compartment::Slot(0), &KEY).unwrap();
+        assert_eq!((read, manifest_len), (payload.as_slice(), 16));
+        assert_eq!(parsed.padding.len(), padding_len(2 * slot_len(5000)));
+    }
+
+    #[test]
+    fn set_payload_never_shrinks_a_slot() {
+        let mut vaultfile = vaultfile_with(&filler_slot(50_000));
+        let len = vaultfile.slots[0].len();
+
+        let payload = filler_slot(100);
+        vaultfile
+            .set_payload(Compartment::Slot(0), &payload, 16, &KEY)
+            .unwrap();
+
+        assert_eq!(vaultfile.slots[0].len(), len);
+        let (read, _) = vaultfile.payload(Compartment::Slot(0), &KEY).unwrap();
+        assert_eq!(read, payload.as_slice());
+    }
+
+    #[test]
+    fn hidden_vault_survives_new_padding() {
+        let mut vaultfile = vaultfile_with(&filler_slot(5000));
+        let hidden = filler_slot(1000);
+        vaultfile.seal_hidden(&hidden, 16, &HIDDEN_KEY).unwrap();
+
+        assert_eq!(vaultfile.find(&HIDDEN_KEY), Some(Compartment::Hidden));
+        assert_eq!(vaultfile.hidden_region(&KEY), None);
+        let region = vaultfile.hidden_region(&HIDDEN_KEY).unwrap().to_vec();
+        assert_eq!(region.len(), hidden.len() + SLOT_HEADER_LEN);
+
+        // Locking the vault renews the padding, keeping the hidden vault it was given.
+        vaultfile.fill_padding(Some(&region)).unwrap();
+        assert_eq!(vaultfile.padding.len(), padding_len(2 * slot_len(5000)));
+        let (read, manifest_len) = vaultfile.payload(Compartment::Hidden, &HIDDEN_KEY).unwrap();
+        assert_eq!((read, manifest_len), (hidden.as_slice(), 16));
+
+        vaultfile.fill_padding(None).unwrap();
+        assert_eq!(vaultfile.find(&HIDDEN_KEY), None);
+    }
+
+    #[test]
+    fn hidden_vault_must_fit_the_padding() {
+        let mut vaultfile = vaultfile_with(&filler_slot(5000));
+        let capacity = vaultfile.hidden_capacity();
+        assert!(vaultfile
+            .seal_hidden(&filler_slot(capacity + 1), 16, &HIDDEN_KEY)
+            .is_err());
+
+        // A hidden vault that filled the padding of a larger vault does not fit a smaller one.
+        let padding = vaultfile.padding.clone();
+        let oversized = filler_slot(padding.len() + 1);
+        assert!(vaultfile.fill_padding(Some(&oversized)).is_err());
+        assert_eq!(vaultfile.padding, padding);
+    }
+}
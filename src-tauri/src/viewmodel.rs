@@ -1,36 +1,347 @@
-use crate::config::Config;
+use crate::config::{Config, VaultEntry};
+use crate::payload::{Checksum, FileEntry, Payload};
+use crate::utils::RETAINED_VAULTFILE;
+use crate::vaultfile::{self, VaultMetadata};
+use crate::volume;
 use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+// Whether the directory of a vault still matches what the configfile says about it.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum VaultStatus {
+    Present,
+    // The directory was moved, renamed or is on a drive that is not mounted.
+    Missing,
+    // The vault is locked but its vaultfile is gone.
+    VaultfileMissing,
+    // The files in the directory say locked while the configfile says unlocked, or the other way.
+    StateMismatch,
+    // The vaultfile cannot be read or belongs to another vault.
+    VaultfileInvalid,
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VaultViewModel {
+    id: String,
     name: String,
     path: String,
     is_locked: bool,
+    is_wiped: bool,
+    status: VaultStatus,
+
+    // Seconds since the unix epoch, None if it never happened or is not known.
+    created_at: Option<u64>,
+    last_locked: Option<u64>,
+    last_unlocked: Option<u64>,
+    last_failed_attempt: Option<u64>,
+    last_verified: Option<u64>,
+
+    // Size of the vaultfile on disk, None if there is none.
+    vaultfile_size: Option<u64>,
+    // None if the vaultfile has no metadata that the metadata key opens.
+    contents: Option<VaultMetadata>,
+}
+
+// A file stored in a vault.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultFileViewModel {
+    name: String,
+    size: u64,
+    // Seconds since the unix epoch, None for files locked before it was kept.
+    modified: Option<u64>,
+}
+
+// A file restored from a vault and how it compares with the hash it was locked with.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoredFileViewModel {
+    name: String,
+    checksum: Checksum,
+}
+
+// The result of verifying a locked vault.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReportViewModel {
+    // Whether the metadata in the vaultfile opens with the vault's metadata key. None if the
+    // vaultfile has none.
+    metadata: Option<bool>,
+    // Whether the header of the vaultfile authenticates and describes this vault. None if the
+    // vaultfile has none.
+    header: Option<bool>,
+    files: Vec<VerifiedFileViewModel>,
+    is_intact: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifiedFileViewModel {
+    name: String,
+    chunks: usize,
+    // None if a chunk could not be decrypted, error says why.
+    checksum: Option<Checksum>,
+    error: Option<String>,
+}
+
+pub const HEADER_PROBLEM: &str =
+    "The header of the vaultfile does not authenticate or describes another vault.";
+
+// A vault that scrubbing found damaged.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DamagedVaultViewModel {
+    id: String,
+    name: String,
+    problem: String,
 }
 
 impl VaultViewModel {
-    fn new(name: &String, path: &String, is_locked: bool) -> Self {
+    fn new(entry: &VaultEntry) -> Self {
         VaultViewModel {
-            name: name.to_string(),
-            path: path.to_string(),
-            is_locked,
+            id: entry.id.to_string(),
+            name: entry.name.to_string(),
+            path: entry.path.to_string(),
+            is_locked: entry.is_locked,
+            is_wiped: entry.is_wiped,
+            status: VaultStatus::of(entry),
+            created_at: entry.created_at,
+            last_locked: entry.last_locked,
+            last_unlocked: entry.last_unlocked,
+            last_failed_attempt: entry.last_failed_attempt,
+            last_verified: entry.last_verified,
+            vaultfile_size: vaultfile_size(entry),
+            contents: read_contents(entry),
         }
     }
 
     // Read the Configfile and return a vector of vaults to the frontend.
     // Call this from a command to make the handle valid
-    pub fn get_from_file(handle: tauri::AppHandle) -> Vec<VaultViewModel> {
-        let configfile = Config::from_json(handle).expect("Could not access configfile!");
+    pub fn get_from_file(handle: tauri::AppHandle) -> Result<Vec<VaultViewModel>, String> {
+        let configfile = Config::from_json(handle)
+            .map_err(|e| format!("Could not read the configfile: {}", e))?;
         let mut result = vec![];
-        for i in 0..configfile.hashes.len() {
-            let vault = VaultViewModel::new(
-                &configfile.names[i],
-                &configfile.paths[i],
-                configfile.is_locked[i],
-            );
+        for entry in &configfile.vaults {
+            let vault = VaultViewModel::new(entry);
             result.push(vault);
         }
-        result
+        Ok(result)
+    }
+}
+
+impl VaultFileViewModel {
+    pub fn new(file: &FileEntry) -> Self {
+        VaultFileViewModel {
+            name: file.name.to_string(),
+            size: file.size,
+            modified: file.modified,
+        }
+    }
+}
+
+impl VerifyReportViewModel {
+    // Decrypt every chunk of every file in the payload and compare the files with their hashes.
+    // Nothing is written to disk.
+    pub fn new(vault: &VaultEntry, payload: &Payload, key: &[u8]) -> Self {
+        let files: Vec<_> = payload
+            .manifest
+            .files
+            .iter()
+            .map(|file| {
+                let (checksum, error) = match payload.read_file(file, key) {
+                    Ok(contents) => (Some(file.check(&contents, key)), None),
+                    Err(e) => (None, Some(e)),
+                };
+                VerifiedFileViewModel {
+                    name: file.name.to_string(),
+                    chunks: file.chunks.len(),
+                    checksum,
+                    error,
+                }
+            })
+            .collect();
+
+        let metadata = check_metadata(vault);
+        let header = check_header(vault, Path::new(&vault.path));
+        let is_intact = metadata != Some(false)
+            && header != Some(false)
+            && files
+                .iter()
+                .all(|file| file.error.is_none() && file.checksum != Some(Checksum::Mismatch));
+
+        VerifyReportViewModel {
+            metadata,
+            header,
+            files,
+            is_intact,
+        }
+    }
+
+    pub fn is_intact(&self) -> bool {
+        self.is_intact
+    }
+
+    // What is damaged, None if the vault is intact.
+    pub fn problem(&self) -> Option<String> {
+        if self.is_intact {
+            return None;
+        }
+        if self.metadata == Some(false) {
+            return Some("The metadata in the vaultfile does not authenticate.".to_string());
+        }
+        if self.header == Some(false) {
+            return Some(HEADER_PROBLEM.to_string());
+        }
+
+        let damaged: Vec<_> = self
+            .files
+            .iter()
+            .filter(|file| file.error.is_some() || file.checksum == Some(Checksum::Mismatch))
+            .map(|file| file.name.as_str())
+            .collect();
+        Some(format!("These files are damaged: {}", damaged.join(", ")))
+    }
+}
+
+impl DamagedVaultViewModel {
+    pub fn new(vault: &VaultEntry, problem: String) -> Self {
+        DamagedVaultViewModel {
+            id: vault.id.to_string(),
+            name: vault.name.to_string(),
+            problem,
+        }
+    }
+}
+
+impl RestoredFileViewModel {
+    pub fn new(name: String, checksum: Checksum) -> Self {
+        RestoredFileViewModel { name, checksum }
+    }
+}
+
+impl VaultStatus {
+    pub fn of(vault: &VaultEntry) -> Self {
+        let path = Path::new(&vault.path);
+        if !path.is_dir() {
+            return VaultStatus::Missing;
+        }
+        // A wiped vault has no vaultfile left to check.
+        if vault.is_wiped {
+            return VaultStatus::Present;
+        }
+
+        let has_vaultfile = volume::has_vaultfile(path);
+        let has_retained = path.join(RETAINED_VAULTFILE).is_file();
+        let status = match (vault.is_locked, has_vaultfile, has_retained) {
+            (true, true, _) => VaultStatus::Present,
+            (true, false, true) => VaultStatus::StateMismatch,
+            (true, false, false) => VaultStatus::VaultfileMissing,
+            (false, true, _) => VaultStatus::StateMismatch,
+            // Vaults unlocked before the vaultfile was retained have neither.
+            (false, false, _) => VaultStatus::Present,
+        };
+
+        // Whatever vaultfile is there has to belong to this vault. Vaultfiles that do not
+        // describe their vault cannot be checked.
+        let vaultfile_path = if has_vaultfile {
+            volume::header_path(path)
+        } else {
+            path.join(RETAINED_VAULTFILE)
+        };
+        if has_vaultfile || has_retained {
+            match vaultfile::read_info(&vaultfile_path) {
+                Ok(Some(info)) if info.id != vault.id => return VaultStatus::VaultfileInvalid,
+                Err(_) => return VaultStatus::VaultfileInvalid,
+                _ => {}
+            }
+        }
+
+        status
+    }
+
+    // What is wrong with the vault and how to fix it. None if nothing is.
+    pub fn problem(&self) -> Option<&'static str> {
+        match self {
+            VaultStatus::Present => None,
+            VaultStatus::Missing => Some(
+                "The vault directory cannot be found. Relink the vault if the directory was \
+                 moved, or connect the drive it is on.",
+            ),
+            VaultStatus::VaultfileMissing => Some(
+                "The vault is locked but its vaultfile is gone. Put the vaultfile back into the \
+                 directory, the files cannot be recovered without it.",
+            ),
+            VaultStatus::StateMismatch => Some(
+                "The vault is marked locked while its directory is unlocked, or the other way \
+                 around. Fix the state to go by what is in the directory.",
+            ),
+            VaultStatus::VaultfileInvalid => Some(
+                "The vaultfile cannot be read or belongs to another vault. Put the vault's own \
+                 vaultfile back into the directory.",
+            ),
+        }
+    }
+}
+
+// The file the header of the vaultfile in dir is read from, the retained one while the vault is
+// unlocked.
+fn vaultfile_path(dir: &Path) -> Option<std::path::PathBuf> {
+    [volume::header_path(dir), dir.join(RETAINED_VAULTFILE)]
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+// Size of a vault's vaultfile on disk, all of its volumes together.
+fn vaultfile_size(vault: &VaultEntry) -> Option<u64> {
+    let path = Path::new(&vault.path);
+    let mut paths = volume::vaultfile_paths(path);
+    if paths.is_empty() {
+        paths.push(path.join(RETAINED_VAULTFILE));
+    }
+
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).ok().map(|metadata| metadata.len()))
+        .sum()
+}
+
+// Whether the metadata in the header of a vault's vaultfile opens with its metadata key.
+// None if there is no metadata to check.
+pub fn check_metadata(vault: &VaultEntry) -> Option<bool> {
+    let header = vaultfile::read_header(&vaultfile_path(Path::new(&vault.path))?).ok()??;
+    let sealed = header.metadata?;
+    let key = Zeroizing::new(hex::decode(&vault.metadata_key).ok()?);
+    Some(key.len() == 32 && VaultMetadata::open(&sealed, &key).is_some())
+}
+
+// Whether the header of the vaultfile in dir was written for the vault: it authenticates with
+// the vault's header key and describes the vault the way the configfile does. A header without
+// a MAC only passes if the vault's header was never written with one. None if there is no header
+// to check.
+pub fn check_header(vault: &VaultEntry, dir: &Path) -> Option<bool> {
+    let header = vaultfile::read_header(&vaultfile_path(dir)?).ok()??;
+    let authentic = match vault.storage().header_key {
+        Some(key) => header.authenticates(&key).unwrap_or(!vault.header_mac),
+        None => true,
+    };
+    let describes = header.vault.as_ref().is_none_or(|info| {
+        info.id == vault.id
+            && info.salt == vault.salt
+            && info.seed.is_none_or(|seed| seed == vault.seed)
+    });
+    Some(authentic && describes)
+}
+
+// Open the metadata in the header of a vault's vaultfile.
+fn read_contents(vault: &VaultEntry) -> Option<VaultMetadata> {
+    let header = vaultfile::read_header(&vaultfile_path(Path::new(&vault.path))?).ok()??;
+    let key = Zeroizing::new(hex::decode(&vault.metadata_key).ok()?);
+    if key.len() != 32 {
+        return None;
     }
+    VaultMetadata::open(&header.metadata?, &key)
 }
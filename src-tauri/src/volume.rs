@@ -0,0 +1,292 @@
+// The vaultfile of a locked vault is either one file named vaultfile, or split into volumes
+// named vaultfile.001, vaultfile.002 and so on so it can be moved on media or through services
+// that limit the size of a file. The volumes are plain pieces of the vaultfile, put back
+// together they are the same bytes.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::utils::{replace_with_temp, write_atomic, write_temp};
+use crate::vaultfile::{self, Volumes};
+
+pub fn volume_path(dir: &Path, number: u32) -> PathBuf {
+    dir.join(format!("vaultfile.{:03}", number))
+}
+
+// The volumes in dir with their numbers, sorted by number.
+pub fn volume_paths(dir: &Path) -> Vec<(u32, PathBuf)> {
+    let Ok(read_dir) = dir.read_dir() else {
+        return vec![];
+    };
+
+    let mut volumes: Vec<_> = read_dir
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((volume_number(entry.file_name().to_str()?)?, entry.path())))
+        .filter(|(_, path)| path.is_file())
+        .collect();
+    volumes.sort();
+    volumes
+}
+
+// The number of the volume a file name belongs to, None if it is not one.
+fn volume_number(name: &str) -> Option<u32> {
+    let number = name.strip_prefix("vaultfile.")?;
+    if number.len() < 3 || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+// Whether a file is named like the vaultfile or one of its volumes. Those are never locked
+// into a vault, they would be overwritten by the vaultfile they are locked into.
+pub fn is_vaultfile_name(name: &str) -> bool {
+    name == "vaultfile" || volume_number(name).is_some()
+}
+
+// Whether dir has the vaultfile of a locked vault, whole or in volumes.
+pub fn has_vaultfile(dir: &Path) -> bool {
+    dir.join("vaultfile").is_file() || !volume_paths(dir).is_empty()
+}
+
+// The file the header of the vaultfile in dir is read from.
+pub fn header_path(dir: &Path) -> PathBuf {
+    if dir.join("vaultfile").is_file() {
+        dir.join("vaultfile")
+    } else {
+        volume_path(dir, 1)
+    }
+}
+
+// Every file the vaultfile in dir is kept in.
+pub fn vaultfile_paths(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = volume_paths(dir)
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect();
+    if dir.join("vaultfile").is_file() {
+        paths.insert(0, dir.join("vaultfile"));
+    }
+    paths
+}
+
+// The numbers of the volumes of the vaultfile in dir that are missing.
+pub fn missing_volumes(dir: &Path) -> Vec<u32> {
+    if dir.join("vaultfile").is_file() {
+        return vec![];
+    }
+
+    let present = volume_paths(dir);
+    if present.is_empty() {
+        return vec![];
+    }
+    let volumes = volumes_of(dir, &present);
+    (1..=volumes.count)
+        .filter(|number| !present.iter().any(|(n, _)| n == number))
+        .collect()
+}
+
+pub fn missing_volumes_error(missing: &[u32]) -> String {
+    let numbers: Vec<_> = missing.iter().map(|number| number.to_string()).collect();
+    format!(
+        "Volume(s) {} of the vaultfile are missing! Put them back into the vault directory.",
+        numbers.join(", ")
+    )
+}
+
+// Read the vaultfile in dir, putting its volumes back together. Missing volumes are left as
+// zeros, so the volumes after them stay where they belong. Reading stops once max_len bytes
+// are read. None if there is no vaultfile at all.
+pub fn read_volumes(dir: &Path, max_len: u64) -> Result<Option<Vec<u8>>, String> {
+    if dir.join("vaultfile").is_file() {
+        return match fs::read(dir.join("vaultfile")) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) => Err(format!("Could not read the vaultfile: {}", e)),
+        };
+    }
+
+    let present = volume_paths(dir);
+    if present.is_empty() {
+        return Ok(None);
+    }
+
+    let volumes = volumes_of(dir, &present);
+    let mut bytes = vec![];
+    for number in 1..=volumes.count {
+        if bytes.len() as u64 >= max_len {
+            break;
+        }
+
+        let mut volume = match present.iter().find(|(n, _)| *n == number) {
+            Some((_, path)) => match fs::read(path) {
+                Ok(volume) => volume,
+                Err(e) => return Err(format!("Could not read volume {}: {}", number, e)),
+            },
+            None => vec![],
+        };
+        if number < volumes.count {
+            volume.resize(volumes.size as usize, 0);
+        }
+        bytes.extend_from_slice(&volume);
+    }
+
+    Ok(Some(bytes))
+}
+
+// Read the vaultfile in dir, putting its volumes back together.
+pub fn read_vaultfile(dir: &Path) -> Result<Vec<u8>, String> {
+    let missing = missing_volumes(dir);
+    if !missing.is_empty() {
+        return Err(missing_volumes_error(&missing));
+    }
+
+    match read_volumes(dir, u64::MAX)? {
+        Some(bytes) => Ok(bytes),
+        None => Err("Could not read the vaultfile: it is missing!".to_string()),
+    }
+}
+
+// Write the bytes of a vaultfile into dir, split into volumes of volume_size if it is larger.
+// Whatever was left of the vaultfile before is removed.
+// Every volume is written to a temporary file and flushed before any of them replaces the
+// vaultfile, so a crash while writing them leaves the vaultfile as it was. The volumes are then
+// renamed into place one at a time, a crash in between leaves a mix of old and new volumes that
+// does not open.
+pub fn write_vaultfile(dir: &Path, bytes: &[u8], volume_size: u64) -> Result<(), String> {
+    if volume_size == 0 || bytes.len() as u64 <= volume_size {
+        if let Err(e) = write_atomic(&dir.join("vaultfile"), bytes) {
+            return Err(format!("Error writing the vaultfile: {}", e));
+        }
+        return remove_volumes(dir, 1);
+    }
+
+    let mut written = vec![];
+    for (volume, number) in bytes.chunks(volume_size as usize).zip(1..) {
+        let path = volume_path(dir, number);
+        match write_temp(&path, volume) {
+            Ok(tmp_path) => written.push((tmp_path, path)),
+            Err(e) => return Err(format!("Error writing volume {}: {}", number, e)),
+        }
+    }
+
+    let count = written.len() as u32;
+    for (tmp_path, path) in written {
+        if let Err(e) = replace_with_temp(&tmp_path, &path) {
+            return Err(format!("Error writing {}: {}", path.display(), e));
+        }
+    }
+
+    if dir.join("vaultfile").is_file() {
+        if let Err(e) = fs::remove_file(dir.join("vaultfile")) {
+            return Err(format!("Could not remove the vaultfile: {}", e));
+        }
+    }
+    remove_volumes(dir, count + 1)
+}
+
+// Remove the volumes in dir from the given number on.
+pub fn remove_volumes(dir: &Path, from: u32) -> Result<(), String> {
+    for (number, path) in volume_paths(dir) {
+        if number < from {
+            continue;
+        }
+        if let Err(e) = fs::remove_file(path) {
+            return Err(format!("Could not remove volume {}: {}", number, e));
+        }
+    }
+
+    Ok(())
+}
+
+// How the vaultfile in dir is split. The header in the first volume says so, if the first
+// volume is as large as it says. Without it, the volumes count up to the last one there is and
+// are as large as the largest one.
+fn volumes_of(dir: &Path, present: &[(u32, PathBuf)]) -> Volumes {
+    let first = volume_path(dir, 1);
+    let header = vaultfile::read_header(&first).ok().flatten();
+    let first_len = fs::metadata(&first).map(|metadata| metadata.len()).ok();
+    if let Some(volumes) = header.and_then(|header| header.volumes) {
+        if first_len == Some(volumes.size) {
+            return volumes;
+        }
+    }
+
+    Volumes {
+        count: present.last().map(|(number, _)| *number).unwrap_or(0),
+        size: present
+            .iter()
+            .filter_map(|(_, path)| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .max()
+            .unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+    use crate::vaultfile::{test_vaultfile, Vaultfile};
+
+    const VOLUME_SIZE: u64 = 4096;
+
+    #[test]
+    fn split_vaultfile_reads_back() {
+        let dir = test_dir();
+        let vaultfile = test_vaultfile();
+        let bytes = vaultfile.to_volume_bytes(VOLUME_SIZE, None);
+        write_vaultfile(&dir, &bytes, VOLUME_SIZE).unwrap();
+
+        assert!(!dir.join("vaultfile").exists());
+        assert_eq!(
+            volume_paths(&dir).len() as u64,
+            (bytes.len() as u64).div_ceil(VOLUME_SIZE)
+        );
+        let read = read_vaultfile(&dir).unwrap();
+        assert_eq!(read, bytes);
+        let parsed = Vaultfile::from_bytes(&read).unwrap().unwrap();
+        assert_eq!(parsed.slots, vaultfile.slots);
+        assert_eq!(parsed.padding, vaultfile.padding);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unsplit_vaultfile_replaces_volumes() {
+        let dir = test_dir();
+        let vaultfile = test_vaultfile();
+        let split = vaultfile.to_volume_bytes(VOLUME_SIZE, None);
+        write_vaultfile(&dir, &split, VOLUME_SIZE).unwrap();
+
+        let bytes = vaultfile.to_volume_bytes(0, None);
+        write_vaultfile(&dir, &bytes, 0).unwrap();
+
+        assert!(volume_paths(&dir).is_empty());
+        assert_eq!(read_vaultfile(&dir).unwrap(), bytes);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_volume() {
+        let dir = test_dir();
+        let bytes = test_vaultfile().to_volume_bytes(VOLUME_SIZE, None);
+        write_vaultfile(&dir, &bytes, VOLUME_SIZE).unwrap();
+        fs::remove_file(volume_path(&dir, 2)).unwrap();
+
+        assert_eq!(missing_volumes(&dir), vec![2]);
+        assert_eq!(
+            read_vaultfile(&dir).unwrap_err(),
+            missing_volumes_error(&[2])
+        );
+
+        // The volumes after the missing one stay where they belong.
+        let read = read_volumes(&dir, u64::MAX).unwrap().unwrap();
+        let (start, end) = (VOLUME_SIZE as usize, 2 * VOLUME_SIZE as usize);
+        assert_eq!(read.len(), bytes.len());
+        assert_eq!(read[..start], bytes[..start]);
+        assert!(read[start..end].iter().all(|&byte| byte == 0));
+        assert_eq!(read[end..], bytes[end..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,28 +5,56 @@ import { invoke } from "@tauri-apps/api/core";
 
 import { useModal } from "../hooks/useModal";
 
+interface RestoredFileViewModel {
+  name: string;
+  checksum: "verified" | "mismatch" | "unknown";
+}
+
 interface CheckPasswordProps {
-  path: string;
+  id: string;
   mode: string;
 }
 
-const CheckPassword = ({ path, mode }: CheckPasswordProps) => {
+const CheckPassword = ({ id, mode }: CheckPasswordProps) => {
   const [verifyPassField, setVerifyPassField] = useState("");
-  const [showIncorrectPass, setShowIncorrectPass] = useState(false);
+  // Locking the vault renews its padding, a hidden vault in it is only kept with its password.
+  const [hiddenPassField, setHiddenPassField] = useState("");
+  const [error, setError] = useState("");
+  const [mismatches, setMismatches] = useState<string[]>([]);
 
   const { close } = useModal("checkPass");
 
   const handleLockUnlock = async () => {
     console.log("Check password");
     const command = `${mode}_vault`; // unlock_vault or lock_vault
-    const params = { path: path, password: verifyPassField };
+    const params =
+      mode === "lock"
+        ? {
+            id: id,
+            password: verifyPassField,
+            hiddenPassword: hiddenPassField || null,
+          }
+        : { id: id, password: verifyPassField };
 
     try {
-      await invoke(command, params);
-    } catch {
-      setShowIncorrectPass(true);
-    } finally {
+      const restored: RestoredFileViewModel[] | null = await invoke(
+        command,
+        params
+      );
+
+      // Keep the modal open to report files that do not match what was locked.
+      const failed = (restored ?? [])
+        .filter((file) => file.checksum === "mismatch")
+        .map((file) => file.name);
+      if (failed.length > 0) {
+        setMismatches(failed);
+        return;
+      }
       close();
+    } catch (e) {
+      // Keep the modal open, the error may be more than a wrong password, such as a wiped vault.
+      console.log(e);
+      setError(`${e}`);
     }
   };
 
@@ -34,8 +62,13 @@ const CheckPassword = ({ path, mode }: CheckPasswordProps) => {
 
   return (
     <div className="absolute top-0 left-0 w-full h-full flex justify-center items-center z-20 bg-white/10">
-      <form className="flex flex-col relative w-[400px] h-[147px] bg-black rounded-lg">
-        {showIncorrectPass && <IncorrectPassword />}
+      <form className="flex flex-col relative w-[400px] min-h-[147px] bg-black rounded-lg">
+        {error && <div className="text-red-500 text-center py-2">{error}</div>}
+        {mismatches.length > 0 && (
+          <div className="text-red-500 text-center py-2">
+            These files do not match what was locked: {mismatches.join(", ")}
+          </div>
+        )}
         <div className="flex flex-col top-4 left-4 pt-4 px-4">
           <h1 className="text-2xl font-bold">{title}</h1>
           <input
@@ -45,6 +78,15 @@ const CheckPassword = ({ path, mode }: CheckPasswordProps) => {
             placeholder="Password"
             onChange={(e) => setVerifyPassField(e.target.value)}
           />
+          {mode === "lock" && (
+            <input
+              className="shadow appearance-none border border-white/20 bg-black rounded w-full py-2 px-3 text-white leading-tight focus:outline-none focus:shadow-outline text-xl mt-2"
+              id="hiddenPassword"
+              type="password"
+              placeholder="Hidden vault password (keeps it)"
+              onChange={(e) => setHiddenPassField(e.target.value)}
+            />
+          )}
         </div>
         <div className="flex flex-row justify-end items-center w-full py-1 px-4">
           <Button onClick={async () => handleLockUnlock()}>
@@ -64,12 +106,4 @@ const CheckPassword = ({ path, mode }: CheckPasswordProps) => {
   );
 };
 
-const IncorrectPassword: React.FC = () => {
-  return (
-    <div className="text-red-500 text-center py-2">
-      Incorrect password. Please try again.
-    </div>
-  );
-};
-
 export default CheckPassword;
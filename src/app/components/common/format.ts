@@ -0,0 +1,15 @@
+export const formatSize = (bytes: number | null) => {
+  if (bytes === null) return "Unknown";
+  const units = ["B", "KB", "MB", "GB", "TB"];
+  let size = bytes;
+  let unit = 0;
+  while (size >= 1024 && unit < units.length - 1) {
+    size /= 1024;
+    unit++;
+  }
+  return `${size.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+};
+
+// Timestamps come from the backend as seconds since the unix epoch.
+export const formatTime = (seconds: number | null) =>
+  seconds === null ? "Unknown" : new Date(seconds * 1000).toLocaleString();
@@ -0,0 +1,87 @@
+import { useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import Button from "./common/button";
+import { Check, X } from "@geist-ui/icons";
+import { useModal } from "../hooks/useModal";
+
+// Asks for the name and password of the vault in a directory. The vaultfile keeps neither, the
+// password is checked against it. Vaults found by a scan are imported one after another.
+const ImportVault = ({
+  dirs,
+  onDone,
+}: {
+  dirs: string[];
+  onDone: (dir: string) => void;
+}) => {
+  const [name, setName] = useState("");
+  const [password, setPassword] = useState("");
+  const [error, setError] = useState("");
+
+  const { close } = useModal("importVault");
+
+  const dir = dirs[0];
+
+  // Move on to the next directory, closing once there are none left.
+  const next = () => {
+    setName("");
+    setPassword("");
+    setError("");
+    onDone(dir);
+    if (dirs.length <= 1) {
+      close();
+    }
+  };
+
+  const importVault = async () => {
+    try {
+      await invoke("import_vault", {
+        path: dir,
+        name: name,
+        password: password,
+      });
+      next();
+    } catch (e) {
+      console.log(e);
+      setError(`${e}`);
+    }
+  };
+
+  return (
+    <div className="absolute top-0 left-0 w-full h-full flex justify-center items-center z-10 bg-white/10">
+      <form className="flex flex-col relative w-[400px] min-h-[250px] bg-black rounded-lg">
+        <p className="pt-4 px-4 text-white/50 break-all">{dir}</p>
+        <div className="flex flex-col top-4 left-4 pt-4 px-4">
+          <h1 className="text-2xl font-bold">Name</h1>
+          <input
+            className="shadow appearance-none border border-white/20 bg-black rounded w-full py-2 px-3 text-white leading-tight focus:outline-none focus:shadow-outline text-xl mt-2"
+            type="text"
+            placeholder="Name for the vault"
+            value={name}
+            onChange={(e) => setName(e.target.value)}
+          />
+        </div>
+        <div className="flex flex-col top-4 left-4 pt-4 px-4">
+          <h1 className="text-2xl font-bold">Password</h1>
+          <input
+            className="shadow appearance-none border border-white/20 bg-black rounded w-full py-2 px-3 text-white leading-tight focus:outline-none focus:shadow-outline text-xl mt-2"
+            type="password"
+            placeholder="Password"
+            value={password}
+            onChange={(e) => setPassword(e.target.value)}
+          />
+        </div>
+        {error && <div className="text-red-500 text-center py-2">{error}</div>}
+        <div className="flex flex-row justify-end items-center w-full py-2 px-4">
+          <Button onClick={async () => await importVault()}>
+            <Check />
+          </Button>
+          <Button onClick={next}>
+            <X />
+          </Button>
+        </div>
+      </form>
+    </div>
+  );
+};
+
+export default ImportVault;
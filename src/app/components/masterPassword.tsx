@@ -0,0 +1,94 @@
+import Button from "./common/button";
+import { useState } from "react";
+import { Check, X } from "@geist-ui/icons";
+import { invoke } from "@tauri-apps/api/core";
+
+import { useModal } from "../hooks/useModal";
+
+interface MasterPasswordProps {
+  mode: string; // "unlock" at launch, "set" to set or change the master password
+  isEncrypted: boolean;
+  onUnlocked?: () => void;
+}
+
+const MasterPassword = ({
+  mode,
+  isEncrypted,
+  onUnlocked,
+}: MasterPasswordProps) => {
+  const [currentPassField, setCurrentPassField] = useState("");
+  const [newPassField, setNewPassField] = useState("");
+  const [error, setError] = useState("");
+
+  const { close } = useModal("masterPassword");
+
+  const inputClass =
+    "shadow appearance-none border border-white/20 bg-black rounded w-full py-2 px-3 text-white leading-tight focus:outline-none focus:shadow-outline text-xl mt-2";
+
+  const handleSubmit = async () => {
+    try {
+      if (mode === "unlock") {
+        await invoke("unlock_master_password", { password: currentPassField });
+        // A damaged configfile may have been restored from a backup.
+        onUnlocked?.();
+      } else {
+        await invoke("set_master_password", {
+          currentPassword: isEncrypted ? currentPassField : null,
+          newPassword: newPassField,
+        });
+      }
+      close();
+    } catch (e) {
+      setError(String(e));
+    }
+  };
+
+  const title =
+    mode === "unlock"
+      ? "Enter master password"
+      : isEncrypted
+      ? "Change master password"
+      : "Set master password";
+
+  return (
+    <div className="absolute top-0 left-0 w-full h-full flex justify-center items-center z-30 bg-white/10">
+      <form className="flex flex-col relative w-[400px] bg-black rounded-lg">
+        {error && <div className="text-red-500 text-center py-2">{error}</div>}
+        <div className="flex flex-col top-4 left-4 pt-4 px-4">
+          <h1 className="text-2xl font-bold">{title}</h1>
+          {(mode === "unlock" || isEncrypted) && (
+            <input
+              className={inputClass}
+              id="current-password"
+              type="password"
+              placeholder={mode === "unlock" ? "Master password" : "Current password"}
+              onChange={(e) => setCurrentPassField(e.target.value)}
+            />
+          )}
+          {mode === "set" && (
+            <input
+              className={inputClass}
+              id="new-password"
+              type="password"
+              placeholder="New password"
+              onChange={(e) => setNewPassField(e.target.value)}
+            />
+          )}
+        </div>
+        <div className="flex flex-row justify-end items-center w-full py-1 px-4">
+          <Button onClick={async () => handleSubmit()}>
+            <Check />
+          </Button>
+          {/* The app cannot be used before the master password is entered */}
+          {mode === "set" && (
+            <Button onClick={close}>
+              <X />
+            </Button>
+          )}
+        </div>
+      </form>
+    </div>
+  );
+};
+
+export default MasterPassword;
@@ -1,20 +1,54 @@
 import { useState, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/core";
-import { X, Trash2, Lock, Unlock } from "@geist-ui/icons";
+import { open } from "@tauri-apps/plugin-dialog";
+import { X, Trash2, Lock, Unlock, List } from "@geist-ui/icons";
 
 import Button from "../components/common/button";
+import { formatSize, formatTime } from "./common/format";
 import CheckPassword from "./checkPassword";
+import VaultContents from "./vaultContents";
 import { useModal } from "../hooks/useModal";
 
 interface VaultViewModel {
+  id: string;
   name: string;
   path: string;
   isLocked: boolean;
+  status:
+    | "present"
+    | "missing"
+    | "vaultfileMissing"
+    | "stateMismatch"
+    | "vaultfileInvalid";
+  createdAt: number | null;
+  lastLocked: number | null;
+  lastUnlocked: number | null;
+  lastFailedAttempt: number | null;
+  lastVerified: number | null;
+  vaultfileSize: number | null;
+  contents: {
+    fileCount: number;
+    plaintextSize: number;
+    cipher: string;
+    kdf: string;
+  } | null;
 }
 
+const statusText = {
+  present: "",
+  missing: "Directory missing",
+  vaultfileMissing: "Vaultfile missing",
+  stateMismatch: "Lock state mismatch",
+  vaultfileInvalid: "Vaultfile invalid",
+};
+
 interface VaultItemProps extends VaultViewModel {
   onDelete: () => void;
-  onToggleLock: (path: string) => void;
+  onToggleLock: (id: string) => void;
+  onRelink: () => void;
+  onReconcile: () => void;
+  onRepair: () => void;
+  onShowContents: (id: string) => void;
 }
 
 const SelectVaults = () => {
@@ -27,8 +61,9 @@ const SelectVaults = () => {
 
   const { close } = useModal("vaults");
   const { isOpen: checkPassOpen } = useModal("checkPass");
+  const { isOpen: vaultContentsOpen } = useModal("vaultContents");
 
-  const [path, setPath] = useState("");
+  const [id, setId] = useState("");
 
   const getVaults = async () => {
     const vaults: VaultViewModel[] = await invoke("get_vaults");
@@ -39,17 +74,43 @@ const SelectVaults = () => {
     getVaults();
   }, []);
 
-  const deleteVault = async (path: string) => {
-    // Issue a command to remove the vault of the given path
-    await invoke("remove_vault", { path: path });
+  const deleteVault = async (id: string) => {
+    // Issue a command to remove the vault of the given id
+    await invoke("remove_vault", { id: id });
 
     // update the vaults state to trigger a re-render
     getVaults();
   };
 
+  const reconcileVault = async (id: string) => {
+    // Go by the lock state of the files in the vault directory
+    await invoke("reconcile_vault", { id: id });
+    getVaults();
+  };
+
+  const repairVault = async (id: string) => {
+    // Rebuild the damaged parts of the vaultfile from its parity
+    await invoke<number>("repair_vault", { id: id });
+    getVaults();
+  };
+
+  const relinkVault = async (id: string) => {
+    // Ask for the directory the vault was moved to
+    const dir = await open({
+      multiple: false,
+      directory: true,
+    });
+
+    if (dir) {
+      await invoke("relink_vault", { id: id, path: dir });
+      getVaults();
+    }
+  };
+
   return (
     <>
-      {checkPassOpen && <CheckPassword path={path} mode={checkPassMode} />}
+      {checkPassOpen && <CheckPassword id={id} mode={checkPassMode} />}
+      {vaultContentsOpen && <VaultContents id={id} />}
       <div className="absolute top-0 left-0 w-full h-full flex justify-center items-center z-10 bg-white/10">
         <div className="relative w-[700px] h-[500px] bg-black text-white rounded-lg">
           {/* Header Section */}
@@ -68,27 +129,48 @@ const SelectVaults = () => {
           {/* Modal Content */}
           <div className="overflow-y-auto h-[calc(97%-4rem)]">
             {vaults.length > 0 ? (
-              vaults.map(({ name, path, isLocked }: VaultViewModel, key) => {
+              vaults.map((vault: VaultViewModel) => {
+                const { id, isLocked } = vault;
                 return (
                   <VaultItem
                     onDelete={async () => {
                       try {
-                        await deleteVault(path);
+                        await deleteVault(id);
                       } catch (e) {
                         console.log(e);
                       }
                     }}
-                    onToggleLock={async (path: string) => {
-                      setPath(path);
+                    onToggleLock={async (id: string) => {
+                      setId(id);
 
                       // Open in "unlock" mode if the vault is locked.
                       // Open in "lock" mode if the vault is unlocked.
                       setCheckPassMode(isLocked ? "unlock" : "lock");
                     }}
-                    name={name}
-                    path={path}
-                    isLocked={isLocked}
-                    key={key}
+                    onRelink={async () => {
+                      try {
+                        await relinkVault(id);
+                      } catch (e) {
+                        console.log(e);
+                      }
+                    }}
+                    onReconcile={async () => {
+                      try {
+                        await reconcileVault(id);
+                      } catch (e) {
+                        console.log(e);
+                      }
+                    }}
+                    onRepair={async () => {
+                      try {
+                        await repairVault(id);
+                      } catch (e) {
+                        console.log(e);
+                      }
+                    }}
+                    onShowContents={(id: string) => setId(id)}
+                    {...vault}
+                    key={id}
                   />
                 );
               })
@@ -105,15 +187,29 @@ const SelectVaults = () => {
 };
 
 const VaultItem = ({
+  id,
   name,
   path,
   isLocked,
+  status,
+  createdAt,
+  lastLocked,
+  lastUnlocked,
+  lastFailedAttempt,
+  lastVerified,
+  vaultfileSize,
+  contents,
   onDelete,
   onToggleLock,
+  onRelink,
+  onReconcile,
+  onRepair,
+  onShowContents,
 }: VaultItemProps) => {
   const subText = "font-thin text-sm text-white/50";
 
   const { open: openCheckPass } = useModal("checkPass");
+  const { open: openVaultContents } = useModal("vaultContents");
 
   return (
     <div className="flex justify-between items-center w-full px-4 py-4 border-b border-white/10 ">
@@ -124,17 +220,63 @@ const VaultItem = ({
         <button
           className="flex flex-col"
           onClick={() => {
-            onToggleLock(path);
+            onToggleLock(id);
             openCheckPass();
           }}
         >
           <h1 className="font-bold text-lg">{name}</h1>
           <h2 className={subText}>{path}</h2>
+          <h2
+            className={subText}
+            title={
+              contents
+                ? `${contents.cipher}, ${contents.kdf}\nLast verified: ${formatTime(
+                    lastVerified
+                  )}`
+                : `Last verified: ${formatTime(lastVerified)}`
+            }
+          >
+            {contents
+              ? `${contents.fileCount} files, ${formatSize(
+                  contents.plaintextSize
+                )} in ${formatSize(vaultfileSize)}`
+              : formatSize(vaultfileSize)}
+          </h2>
+          {status !== "present" && (
+            <h2 className="text-sm text-red-500">{statusText[status]}</h2>
+          )}
         </button>
+        {status === "missing" && (
+          <Button onClick={() => onRelink()}>Relink</Button>
+        )}
+        {status === "stateMismatch" && (
+          <Button onClick={() => onReconcile()}>Fix state</Button>
+        )}
+        {isLocked &&
+          (status === "vaultfileMissing" || status === "vaultfileInvalid") && (
+            <Button onClick={() => onRepair()}>Repair</Button>
+          )}
+        {isLocked && status === "present" && (
+          <Button
+            onClick={() => {
+              onShowContents(id);
+              openVaultContents();
+            }}
+          >
+            <List />
+          </Button>
+        )}
       </div>
       {isLocked ? <Lock /> : <Unlock />}
-      <h2 className={subText}>Creation date</h2>
-      <h2 className={subText}>Last accessed</h2>
+      <h2 className={subText}>{formatTime(createdAt)}</h2>
+      <h2
+        className={subText}
+        title={`Locked: ${formatTime(lastLocked)}\nUnlocked: ${formatTime(
+          lastUnlocked
+        )}\nLast failed attempt: ${formatTime(lastFailedAttempt)}`}
+      >
+        {formatTime(Math.max(lastLocked ?? 0, lastUnlocked ?? 0) || null)}
+      </h2>
     </div>
   );
 };
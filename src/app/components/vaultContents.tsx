@@ -0,0 +1,470 @@
+import Button from "./common/button";
+import { useState } from "react";
+import { Check, X } from "@geist-ui/icons";
+import { invoke } from "@tauri-apps/api/core";
+import { open } from "@tauri-apps/plugin-dialog";
+
+import { formatSize, formatTime } from "./common/format";
+import { useModal } from "../hooks/useModal";
+
+interface VaultFileViewModel {
+  name: string;
+  size: number;
+  modified: number | null;
+}
+
+interface RestoredFileViewModel {
+  name: string;
+  checksum: "verified" | "mismatch" | "unknown";
+}
+
+interface VerifiedFileViewModel {
+  name: string;
+  chunks: number;
+  checksum: "verified" | "mismatch" | "unknown" | null;
+  error: string | null;
+}
+
+interface VerifyReportViewModel {
+  metadata: boolean | null;
+  header: boolean | null;
+  files: VerifiedFileViewModel[];
+  isIntact: boolean;
+}
+
+interface VaultContentsProps {
+  id: string;
+}
+
+// Lists the files of a locked vault, extracts or removes the selected ones, replaces them and
+// adds new ones. The vault stays locked.
+const VaultContents = ({ id }: VaultContentsProps) => {
+  const [passwordField, setPasswordField] = useState("");
+  const [files, setFiles] = useState<VaultFileViewModel[] | null>(null);
+  const [selected, setSelected] = useState<string[]>([]);
+  const [error, setError] = useState("");
+  const [message, setMessage] = useState("");
+  const [keepVerifying, setKeepVerifying] = useState(false);
+  const [hiddenPasswordField, setHiddenPasswordField] = useState("");
+
+  const { close } = useModal("vaultContents");
+
+  const listContents = async () => {
+    try {
+      const files: VaultFileViewModel[] = await invoke("list_vault_contents", {
+        id: id,
+        password: passwordField,
+      });
+      setFiles(files);
+      setError("");
+    } catch (e) {
+      console.log(e);
+      setError(`${e}`);
+    }
+  };
+
+  const verifyVault = async () => {
+    try {
+      const report: VerifyReportViewModel = await invoke("verify_vault", {
+        id: id,
+        password: passwordField,
+        remember: keepVerifying,
+      });
+
+      if (report.isIntact) {
+        setMessage("The vault is intact.");
+        setError("");
+      } else {
+        const failed = report.files
+          .filter((file) => file.error !== null || file.checksum === "mismatch")
+          .map((file) => file.name);
+        if (report.metadata === false) {
+          failed.push("metadata");
+        }
+        if (report.header === false) {
+          failed.push("vaultfile header");
+        }
+        setMessage("");
+        setError(`The vault is damaged: ${failed.join(", ")}`);
+      }
+    } catch (e) {
+      console.log(e);
+      setMessage("");
+      setError(`${e}`);
+    }
+  };
+
+  const repairVault = async () => {
+    try {
+      const repaired: number = await invoke("repair_vault", { id: id });
+      setMessage(
+        repaired > 0
+          ? `Rebuilt ${repaired} damaged piece(s) of the vaultfile.`
+          : "Nothing to repair."
+      );
+      setError("");
+    } catch (e) {
+      console.log(e);
+      setMessage("");
+      setError(`${e}`);
+    }
+  };
+
+  const changeParity = async (percent: number) => {
+    try {
+      await invoke("set_parity_redundancy", {
+        id: id,
+        password: passwordField,
+        percent: percent,
+      });
+      setMessage(
+        percent > 0
+          ? `Up to ${percent}% of the vaultfile can now be repaired.`
+          : "The vault has no parity now."
+      );
+      setError("");
+    } catch (e) {
+      console.log(e);
+      setMessage("");
+      setError(`${e}`);
+    }
+  };
+
+  const changeVolumeSize = async (size: number) => {
+    try {
+      await invoke("set_volume_size", {
+        id: id,
+        password: passwordField,
+        size: size,
+      });
+      setMessage(
+        size > 0
+          ? `The vaultfile is split into volumes of ${formatSize(size)}.`
+          : "The vaultfile is kept in one file."
+      );
+      setError("");
+    } catch (e) {
+      console.log(e);
+      setMessage("");
+      setError(`${e}`);
+    }
+  };
+
+  const changeWipeAfter = async (attempts: number) => {
+    try {
+      await invoke("set_wipe_after", {
+        id: id,
+        password: passwordField,
+        attempts: attempts,
+      });
+      setMessage(
+        attempts > 0
+          ? `The vault is wiped after ${attempts} failed unlock attempts in a row.`
+          : "The vault is never wiped."
+      );
+      setError("");
+    } catch (e) {
+      console.log(e);
+      setMessage("");
+      setError(`${e}`);
+    }
+  };
+
+  const changeCompressionLevel = async (level: number) => {
+    try {
+      await invoke("set_compression_level", {
+        id: id,
+        password: passwordField,
+        level: level,
+      });
+      setMessage(
+        level > 0
+          ? `Files encrypted from now on are compressed at level ${level}.`
+          : "Files encrypted from now on are not compressed."
+      );
+      setError("");
+    } catch (e) {
+      console.log(e);
+      setMessage("");
+      setError(`${e}`);
+    }
+  };
+
+  // Put the files of a directory into the padding of the vault as a hidden vault.
+  const createHiddenVault = async () => {
+    const hiddenPath = await open({
+      multiple: false,
+      directory: true,
+    });
+
+    if (hiddenPath) {
+      try {
+        await invoke("create_hidden_vault", {
+          id: id,
+          password: passwordField,
+          hiddenPassword: hiddenPasswordField,
+          hiddenPath: hiddenPath,
+        });
+        setMessage(
+          "The hidden vault was created. Give its password when locking the vault to keep it."
+        );
+        setError("");
+      } catch (e) {
+        console.log(e);
+        setMessage("");
+        setError(`${e}`);
+      }
+    }
+  };
+
+  const toggleSelected = (name: string) => {
+    setSelected((prev) =>
+      prev.includes(name) ? prev.filter((n) => n !== name) : [...prev, name]
+    );
+  };
+
+  const extractSelected = async () => {
+    const dest = await open({
+      multiple: false,
+      directory: true,
+    });
+
+    if (dest) {
+      try {
+        const restored: RestoredFileViewModel[] = await invoke(
+          "extract_from_vault",
+          {
+            id: id,
+            password: passwordField,
+            names: selected,
+            dest: dest,
+          }
+        );
+        setSelected([]);
+
+        const failed = restored
+          .filter((file) => file.checksum === "mismatch")
+          .map((file) => file.name);
+        setError(
+          failed.length > 0
+            ? `These files do not match what was locked: ${failed.join(", ")}`
+            : ""
+        );
+      } catch (e) {
+        console.log(e);
+        setError(`${e}`);
+      }
+    }
+  };
+
+  const addFiles = async () => {
+    const paths = await open({
+      multiple: true,
+      directory: false,
+    });
+
+    if (paths) {
+      try {
+        await invoke("add_to_vault", {
+          id: id,
+          password: passwordField,
+          paths: paths,
+        });
+        await listContents();
+      } catch (e) {
+        console.log(e);
+        setError(`${e}`);
+      }
+    }
+  };
+
+  const removeSelected = async () => {
+    try {
+      await invoke("remove_from_vault", {
+        id: id,
+        password: passwordField,
+        names: selected,
+      });
+      setSelected([]);
+      await listContents();
+    } catch (e) {
+      console.log(e);
+      setError(`${e}`);
+    }
+  };
+
+  const replaceFile = async (name: string) => {
+    const path = await open({
+      multiple: false,
+      directory: false,
+    });
+
+    if (path) {
+      try {
+        await invoke("replace_in_vault", {
+          id: id,
+          password: passwordField,
+          name: name,
+          path: path,
+        });
+        await listContents();
+      } catch (e) {
+        console.log(e);
+        setError(`${e}`);
+      }
+    }
+  };
+
+  return (
+    <div className="absolute top-0 left-0 w-full h-full flex justify-center items-center z-20 bg-white/10">
+      <div className="flex flex-col relative w-[500px] max-h-[400px] bg-black text-white rounded-lg">
+        <div className="flex flex-row justify-between items-center pt-4 px-4">
+          <h1 className="text-2xl font-bold">Vault contents</h1>
+          <Button onClick={close}>
+            <X />
+          </Button>
+        </div>
+        {files === null ? (
+          <div className="flex flex-row items-center px-4 py-2">
+            <input
+              className="shadow appearance-none border border-white/20 bg-black rounded w-full py-2 px-3 text-white leading-tight focus:outline-none focus:shadow-outline text-xl"
+              type="password"
+              placeholder="Password"
+              onChange={(e) => setPasswordField(e.target.value)}
+            />
+            <Button onClick={async () => listContents()}>
+              <Check />
+            </Button>
+          </div>
+        ) : (
+          <div className="overflow-y-auto px-4 py-2">
+            {files.length > 0 ? (
+              files.map((file) => (
+                <div
+                  className="flex justify-between py-1 border-b border-white/10"
+                  key={file.name}
+                >
+                  <label className="flex items-center space-x-2">
+                    <input
+                      type="checkbox"
+                      checked={selected.includes(file.name)}
+                      onChange={() => toggleSelected(file.name)}
+                    />
+                    <span>{file.name}</span>
+                  </label>
+                  <div className="flex items-center space-x-2">
+                    <span className="font-thin text-sm text-white/50">
+                      {formatSize(file.size)}, {formatTime(file.modified)}
+                    </span>
+                    <Button onClick={async () => replaceFile(file.name)}>
+                      Replace
+                    </Button>
+                  </div>
+                </div>
+              ))
+            ) : (
+              <p className="text-center m-4 text-gray-400">
+                The vault is empty.
+              </p>
+            )}
+          </div>
+        )}
+        {files !== null && (
+          <div className="flex justify-end items-center px-4 py-2">
+            <label className="flex items-center space-x-2 text-sm text-white/50">
+              <input
+                type="checkbox"
+                checked={keepVerifying}
+                onChange={() => setKeepVerifying(!keepVerifying)}
+              />
+              <span>Keep verifying in the background</span>
+            </label>
+            <Button onClick={async () => verifyVault()}>Verify</Button>
+            <Button onClick={async () => repairVault()}>Repair</Button>
+            <select
+              className="rounded bg-black border border-white/20 p-2"
+              value=""
+              onChange={(e) => changeParity(Number(e.target.value))}
+            >
+              <option value="" disabled>
+                Parity
+              </option>
+              <option value={0}>None</option>
+              <option value={5}>5%</option>
+              <option value={10}>10%</option>
+              <option value={25}>25%</option>
+              <option value={100}>100%</option>
+            </select>
+            <select
+              className="rounded bg-black border border-white/20 p-2"
+              value=""
+              onChange={(e) => changeVolumeSize(Number(e.target.value))}
+            >
+              <option value="" disabled>
+                Volumes
+              </option>
+              <option value={0}>One file</option>
+              <option value={100 * 1024 * 1024}>100 MB</option>
+              <option value={1024 * 1024 * 1024}>1 GB</option>
+              <option value={4 * 1024 * 1024 * 1024 - 1}>4 GB (FAT32)</option>
+            </select>
+            <select
+              className="rounded bg-black border border-white/20 p-2"
+              value=""
+              onChange={(e) => changeWipeAfter(Number(e.target.value))}
+            >
+              <option value="" disabled>
+                Wipe after
+              </option>
+              <option value={0}>Never</option>
+              <option value={3}>3 failures</option>
+              <option value={5}>5 failures</option>
+              <option value={10}>10 failures</option>
+            </select>
+            <select
+              className="rounded bg-black border border-white/20 p-2"
+              value=""
+              onChange={(e) => changeCompressionLevel(Number(e.target.value))}
+            >
+              <option value="" disabled>
+                Compression
+              </option>
+              <option value={0}>None</option>
+              <option value={1}>Fast</option>
+              <option value={3}>Default</option>
+              <option value={19}>Smallest</option>
+            </select>
+            <Button onClick={async () => addFiles()}>Add files</Button>
+            <input
+              className="rounded bg-black border border-white/20 p-2"
+              type="password"
+              placeholder="Hidden password"
+              onChange={(e) => setHiddenPasswordField(e.target.value)}
+            />
+            {hiddenPasswordField && (
+              <Button onClick={async () => createHiddenVault()}>
+                Create hidden vault
+              </Button>
+            )}
+            {selected.length > 0 && (
+              <>
+                <Button onClick={async () => extractSelected()}>
+                  Extract {selected.length} file(s)
+                </Button>
+                <Button onClick={async () => removeSelected()}>
+                  Remove {selected.length} file(s)
+                </Button>
+              </>
+            )}
+          </div>
+        )}
+        {message && (
+          <div className="text-green-500 text-center py-2">{message}</div>
+        )}
+        {error && <div className="text-red-500 text-center py-2">{error}</div>}
+      </div>
+    </div>
+  );
+};
+
+export default VaultContents;
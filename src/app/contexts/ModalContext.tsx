@@ -22,6 +22,8 @@ export const ModalProvider = ({
     vaults: false,
     enterCredentials: false,
     checkPass: false,
+    vaultContents: false,
+    masterPassword: false,
   });
 
   const openModal = (modalId: string) => {
@@ -1,12 +1,27 @@
 "use client";
 
-import { useState } from "react";
+import { useState, useEffect } from "react";
 import { open } from "@tauri-apps/plugin-dialog";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 
 import SelectVaults from "./components/selectVaults";
 import EnterCredentials from "./components/enterCredentials";
+import ImportVault from "./components/importVault";
+import MasterPassword from "./components/masterPassword";
 import { useModal } from "./hooks/useModal";
 
+interface MasterPasswordStatus {
+  isEncrypted: boolean;
+  isUnlocked: boolean;
+}
+
+interface DamagedVaultViewModel {
+  id: string;
+  name: string;
+  problem: string;
+}
+
 interface MenuButtonProps {
   title: string;
   action: () => void;
@@ -21,6 +36,61 @@ export default function Home() {
 
   const { isOpen: vaultsIsOpen, open: openVaults } = useModal("vaults");
 
+  // Directories of vaults waiting to be imported, each needs its name and password.
+  const [importDirs, setImportDirs] = useState<string[]>([]);
+  const { isOpen: importVaultIsOpen, open: openImportVault } =
+    useModal("importVault");
+
+  const { isOpen: masterPasswordIsOpen, open: openMasterPassword } =
+    useModal("masterPassword");
+
+  // "unlock" when asking for the master password at launch, "set" otherwise
+  const [masterPasswordMode, setMasterPasswordMode] = useState("unlock");
+  const [configIsEncrypted, setConfigIsEncrypted] = useState(false);
+  const [notices, setNotices] = useState<string[]>([]);
+  const [scrubInterval, setScrubInterval] = useState(0);
+
+  const checkMasterPassword = async () => {
+    const status: MasterPasswordStatus = await invoke(
+      "get_master_password_status"
+    );
+    setConfigIsEncrypted(status.isEncrypted);
+
+    if (status.isEncrypted && !status.isUnlocked) {
+      setMasterPasswordMode("unlock");
+      openMasterPassword();
+    }
+  };
+
+  useEffect(() => {
+    invoke<string[]>("get_startup_notices").then(setNotices);
+    checkMasterPassword();
+    invoke<number>("get_scrub_interval")
+      .then(setScrubInterval)
+      .catch(console.log);
+  }, []);
+
+  // Vaults found damaged by the background checks.
+  useEffect(() => {
+    const unlisten = listen<DamagedVaultViewModel>("vault-damaged", (event) => {
+      const { name, problem } = event.payload;
+      setNotices((prev) => [...prev, `${name}: ${problem}`]);
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  const changeScrubInterval = async (hours: number) => {
+    try {
+      await invoke("set_scrub_interval", { hours });
+      setScrubInterval(hours);
+    } catch (e) {
+      console.log(e);
+    }
+  };
+
   const buttons: MenuButtonProps[] = [
     {
       title: "Create new vault",
@@ -36,12 +106,72 @@ export default function Home() {
         }
       },
     },
+    {
+      title: "Import vault",
+      action: async () => {
+        const dir = await open({
+          multiple: false,
+          directory: true,
+        });
+
+        if (dir) {
+          setImportDirs([dir]);
+          openImportVault();
+        }
+      },
+    },
+    {
+      title: "Scan for vaults",
+      action: async () => {
+        const root = await open({
+          multiple: false,
+          directory: true,
+        });
+
+        if (root) {
+          try {
+            const found: string[] = await invoke("scan_for_vaults", {
+              root,
+            });
+            setNotices([`Found ${found.length} vault(s) to import.`]);
+            if (found.length > 0) {
+              setImportDirs(found);
+              openImportVault();
+            }
+          } catch (e) {
+            console.log(e);
+          }
+        }
+      },
+    },
     {
       title: "Manage vaults",
       action: () => {
         openVaults();
       },
     },
+    {
+      title: "Check vaults now",
+      action: async () => {
+        try {
+          // Damaged vaults arrive as events.
+          const damaged: DamagedVaultViewModel[] = await invoke("scrub_now");
+          if (damaged.length === 0) {
+            setNotices(["No damaged vaults were found."]);
+          }
+        } catch (e) {
+          console.log(e);
+        }
+      },
+    },
+    {
+      title: "Master password",
+      action: async () => {
+        await checkMasterPassword();
+        setMasterPasswordMode("set");
+        openMasterPassword();
+      },
+    },
   ];
 
   return (
@@ -54,6 +184,41 @@ export default function Home() {
 
       {vaultsIsOpen && <SelectVaults />}
 
+      {importVaultIsOpen && importDirs.length > 0 && (
+        <ImportVault
+          dirs={importDirs}
+          onDone={(dir) =>
+            setImportDirs((prev) => prev.filter((other) => other !== dir))
+          }
+        />
+      )}
+
+      {masterPasswordIsOpen && (
+        <MasterPassword
+          mode={masterPasswordMode}
+          isEncrypted={configIsEncrypted}
+          onUnlocked={() =>
+            invoke<string[]>("get_startup_notices").then((unlockNotices) =>
+              setNotices((prev) => [...prev, ...unlockNotices])
+            )
+          }
+        />
+      )}
+
+      {notices.length > 0 && (
+        <div className="absolute top-4 left-1/2 -translate-x-1/2 z-20 max-w-xl rounded-lg bg-red-500/80 p-4 text-white">
+          {notices.map((notice, index) => (
+            <p key={index}>{notice}</p>
+          ))}
+          <button
+            className="mt-2 rounded-lg bg-white/20 px-4 py-1"
+            onClick={() => setNotices([])}
+          >
+            Dismiss
+          </button>
+        </div>
+      )}
+
       {/* Main View */}
       <div
         className={`flex flex-col items-center justify-between h-screen py-20 ${
@@ -80,6 +245,16 @@ export default function Home() {
               </button>
             );
           })}
+          <select
+            className="rounded-lg bg-white/20 py-2 px-4 text-white"
+            value={scrubInterval}
+            onChange={(e) => changeScrubInterval(Number(e.target.value))}
+          >
+            <option value={0}>Background checks off</option>
+            <option value={1}>Check vaults every hour</option>
+            <option value={24}>Check vaults every day</option>
+            <option value={168}>Check vaults every week</option>
+          </select>
         </div>
       </div>
     </div>